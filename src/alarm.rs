@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use crate::{InputSource, WakeUp, cli::AlarmCommand, scheduler, store};
+
+use chrono::{NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+// Where alarms are saved
+const ALARMS: &str = "alarms.json";
+
+/// Wake-up alarm: powers the speaker on and brings the volume up gradually. Alarms are run by the
+/// scheduler, like schedules.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    pub id: u32,
+    /// Local time of day to go off at.
+    pub time: NaiveTime,
+    pub days: Vec<Weekday>,
+    /// Input to switch to; the speaker stays on its current one if unset.
+    pub input: Option<InputSource>,
+    pub start_volume: i32,
+    pub target_volume: i32,
+    /// How long it takes to get from `start_volume` to `target_volume`.
+    pub ramp_secs: u64,
+}
+
+impl Alarm {
+    pub fn wake_up(&self) -> WakeUp {
+        WakeUp {
+            input: self.input,
+            from: self.start_volume,
+            to: self.target_volume,
+            over: Duration::from_secs(self.ramp_secs),
+        }
+    }
+}
+
+pub fn load() -> Vec<Alarm> {
+    store::load(ALARMS).unwrap_or_default()
+}
+
+/// Handle `qaf alarm …`.
+pub fn cli(command: AlarmCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let mut alarms = load();
+    match command {
+        AlarmCommand::List => {
+            if alarms.is_empty() {
+                println!("No alarms");
+            }
+            for alarm in &alarms {
+                println!(
+                    "{:>3}  {}  {:<20}  {:<9}  volume {} → {} over {} min",
+                    alarm.id,
+                    alarm.time.format("%H:%M"),
+                    scheduler::format_days(&alarm.days),
+                    alarm.input.map_or("-", InputSource::to_kef_source),
+                    alarm.start_volume,
+                    alarm.target_volume,
+                    alarm.ramp_secs / 60
+                );
+            }
+        }
+        AlarmCommand::Add {
+            time,
+            days,
+            input,
+            from,
+            to,
+            ramp,
+        } => {
+            let alarm = Alarm {
+                id: alarms.iter().map(|a| a.id).max().unwrap_or(0) + 1,
+                time,
+                days: scheduler::parse_days(&days)?,
+                input,
+                start_volume: from,
+                target_volume: to,
+                ramp_secs: ramp * 60,
+            };
+            let id = alarm.id;
+            alarms.push(alarm);
+            store::save(ALARMS, &alarms)?;
+            println!("Added alarm {id}");
+        }
+        AlarmCommand::Remove { id } => {
+            let before = alarms.len();
+            alarms.retain(|a| a.id != id);
+            if alarms.len() == before {
+                return Err(format!("no alarm {id}").into());
+            }
+            store::save(ALARMS, &alarms)?;
+            println!("Removed alarm {id}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use crate::{Setting, SpeakerCommand, cli::ApiCommand};
+
+use tokio::sync::{mpsc, oneshot};
+
+/// Handle `qaf api …`: pass the request to the speaker controller and pretty-print what comes
+/// back.
+pub async fn cli(
+    command: ApiCommand,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (path, value) = match command {
+        ApiCommand::Get { path } => (path, None),
+        ApiCommand::Set { path, value } => (path, Some(value)),
+        ApiCommand::Browse { path, json } => return browse(path, json, speaker_tx).await,
+    };
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::Raw { path, value, reply })?;
+
+    let json = response.await??;
+    println!("{}", serde_json::to_string_pretty(&json)?);
+    Ok(())
+}
+
+async fn browse(
+    path: String,
+    json: bool,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::Browse { path, reply })?;
+    let settings = response.await??;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&settings)?);
+        return Ok(());
+    }
+    let width = settings.iter().map(|s| s.path.len()).max().unwrap_or(0);
+    for Setting { path, kind, value } in &settings {
+        let value = value
+            .as_ref()
+            .map_or_else(|| "-".to_string(), display_value);
+        println!("{path:<width$}  {kind:<18}  {value}");
+    }
+    Ok(())
+}
+
+/// A typed value for reading: just the value for the simple types (`35` rather than
+/// `{"type":"i32_","i32_":35}`), compact JSON for the rest.
+pub fn display_value(value: &serde_json::Value) -> String {
+    let inner = value["type"].as_str().map(|kind| &value[kind]);
+    match inner {
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(inner) if !inner.is_null() => inner.to_string(),
+        _ => value.to_string(),
+    }
+}
@@ -0,0 +1,159 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{
+    SpeakerCommand, SpeakerInfo,
+    api::display_value,
+    speaker::{DEVICE_NAME, MAXIMUM_VOLUME, USER_SETTINGS},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+/// What `qaf backup` writes: the speaker it came from and its settings by path, in the typed form
+/// the API uses.
+#[derive(Debug, Serialize, Deserialize)]
+struct Backup {
+    speaker: String,
+    model: String,
+    settings: BTreeMap<String, serde_json::Value>,
+}
+
+async fn raw(
+    speaker_tx: &mpsc::UnboundedSender<SpeakerCommand>,
+    path: &str,
+    value: Option<serde_json::Value>,
+) -> Result<Result<serde_json::Value, String>, Box<dyn std::error::Error>> {
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::Raw {
+        path: path.to_string(),
+        value,
+        reply,
+    })?;
+    Ok(response.await?)
+}
+
+// With `volume_limit.sync_speaker` on, qaf sets the speaker's maximum volume itself, and a
+// restored one would hold until the limit changes
+fn managed(path: &str, max_volume_synced: bool) -> bool {
+    let managed = max_volume_synced && path == MAXIMUM_VOLUME;
+    if managed {
+        eprintln!("Skipping {path}: set from volume_limit by qaf");
+    }
+    managed
+}
+
+// The name goes through SetDeviceName rather than Raw, so qaf's idea of the speaker follows it
+async fn rename(
+    speaker_tx: &mpsc::UnboundedSender<SpeakerCommand>,
+    name: &str,
+) -> Result<Result<(), String>, Box<dyn std::error::Error>> {
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::SetDeviceName {
+        name: name.to_string(),
+        reply,
+    })?;
+    Ok(response.await?)
+}
+
+/// Handle `qaf backup`: print every setting in `USER_SETTINGS` the speaker has, as JSON.
+pub async fn backup(
+    max_volume_synced: bool,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+    info: SpeakerInfo,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut settings = BTreeMap::new();
+    for &path in USER_SETTINGS {
+        if managed(path, max_volume_synced) {
+            continue;
+        }
+        match raw(&speaker_tx, path, None).await? {
+            Ok(value) => {
+                settings.insert(path.to_string(), value);
+            }
+            // Not every model or firmware has every setting
+            Err(e) => eprintln!("Skipping {path}: {e}"),
+        }
+    }
+
+    let backup = Backup {
+        speaker: info.name,
+        model: info.model,
+        settings,
+    };
+    println!("{}", serde_json::to_string_pretty(&backup)?);
+    Ok(())
+}
+
+/// Handle `qaf restore`: write back the settings in `file` that differ from the speaker's, or
+/// with `dry_run` just list them. Settings like the EQ differ between models, so a backup from
+/// another model is only restored with `force`.
+pub async fn restore(
+    file: PathBuf,
+    dry_run: bool,
+    force: bool,
+    max_volume_synced: bool,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+    info: SpeakerInfo,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let backup: Backup = serde_json::from_str(&std::fs::read_to_string(&file)?)
+        .map_err(|e| format!("{} is not a qaf backup: {e}", file.display()))?;
+    if backup.model != info.model {
+        if !dry_run && !force {
+            return Err(format!(
+                "{} is from a {}, not a {}; use --force to restore it anyway",
+                file.display(),
+                backup.model,
+                info.model
+            )
+            .into());
+        }
+        eprintln!(
+            "Warning: {} is from a {}, not a {}",
+            file.display(),
+            backup.model,
+            info.model
+        );
+    }
+
+    let mut changed = 0;
+    for (path, value) in &backup.settings {
+        if managed(path, max_volume_synced) {
+            continue;
+        }
+        let current = match raw(&speaker_tx, path, None).await? {
+            Ok(current) => current,
+            Err(e) => {
+                eprintln!("Skipping {path}: {e}");
+                continue;
+            }
+        };
+        if current == *value {
+            continue;
+        }
+
+        println!(
+            "{path}: {} -> {}",
+            display_value(&current),
+            display_value(value)
+        );
+        changed += 1;
+        if dry_run {
+            continue;
+        }
+        let result = match value["string_"].as_str() {
+            Some(name) if path == DEVICE_NAME => rename(&speaker_tx, name).await?,
+            _ => raw(&speaker_tx, path, Some(value.clone())).await?.map(drop),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to set {path}: {e}");
+            changed -= 1;
+        }
+    }
+
+    match (dry_run, changed) {
+        (_, 0) => println!("Nothing to change"),
+        (true, n) => println!("Would change {n} setting(s) on the speaker"),
+        (false, n) => println!("Restored {n} setting(s) from {}", backup.speaker),
+    }
+    Ok(())
+}
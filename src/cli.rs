@@ -0,0 +1,239 @@
+use std::path::PathBuf;
+
+use chrono::NaiveTime;
+use clap::{Parser, Subcommand};
+
+use crate::{FadeCurve, FavouriteId, InputSource, StandbyMode, scheduler::Action};
+
+/// Control KEF speakers from the macOS menubar or from the terminal.
+///
+/// Without a subcommand qaf starts as a menubar app.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file to use instead of the one in the platform config directory.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Full-screen terminal dashboard, for when you're not at the Mac (e.g. over SSH).
+    Tui,
+    /// Manage scheduled actions, run by the menubar app or TUI while it's running.
+    Schedule {
+        #[command(subcommand)]
+        command: ScheduleCommand,
+    },
+    /// Manage wake-up alarms, run by the menubar app or TUI while it's running.
+    Alarm {
+        #[command(subcommand)]
+        command: AlarmCommand,
+    },
+    /// List the favourites stored on the speaker, like internet radio stations.
+    Favourites {
+        /// Print the favourites as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Play a favourite by its number in `favourites` or its name, e.g. `qaf play "office radio"`.
+    Play {
+        #[arg(value_parser = parse_favourite)]
+        favourite: FavouriteId,
+    },
+    /// Play an audio stream or internet radio station by its URL, e.g.
+    /// `qaf play-url http://192.168.1.10:8000/song.mp3`.
+    PlayUrl {
+        #[arg(value_parser = parse_url)]
+        url: String,
+        /// Title shown while it plays; the URL if not given.
+        #[arg(long)]
+        title: Option<String>,
+    },
+    /// Change the volume gradually, e.g. `qaf fade 0 --over 300` to fade out over five minutes.
+    /// Runs until the fade is done; changing the volume some other way stops it.
+    Fade {
+        /// Volume to end up at.
+        #[arg(value_parser = clap::value_parser!(i32).range(0..=100))]
+        volume: i32,
+        /// Seconds to take getting there.
+        #[arg(long, default_value_t = 30)]
+        over: u64,
+        /// How the volume changes along the way.
+        #[arg(long, value_enum, default_value_t = FadeCurve::Linear)]
+        curve: FadeCurve,
+    },
+    /// Show and change the speaker's own settings, like auto-standby and the startup tone.
+    Device {
+        #[command(subcommand)]
+        command: DeviceCommand,
+    },
+    /// Print the speaker's settings as JSON, e.g. `qaf backup > kef.json`.
+    Backup,
+    /// Write settings saved by `backup` back to the speaker.
+    Restore {
+        file: PathBuf,
+        /// Only show what would change.
+        #[arg(long)]
+        dry_run: bool,
+        /// Restore a backup taken from a different model of speaker.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Read and write speaker settings by their API path, including ones qaf doesn't know about.
+    Api {
+        #[command(subcommand)]
+        command: ApiCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ApiCommand {
+    /// Print a setting, e.g. `qaf api get settings:/deviceName`.
+    Get { path: String },
+    /// Write a setting as typed JSON, e.g.
+    /// `qaf api set player:volume '{"type":"i32_","i32_":30}'`.
+    Set {
+        path: String,
+        #[arg(value_parser = parse_json)]
+        value: serde_json::Value,
+    },
+    /// List every setting under a path with its type and value, to see what the firmware offers.
+    Browse {
+        #[arg(default_value = "settings:/")]
+        path: String,
+        /// Print the settings as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DeviceCommand {
+    /// Show the speaker's device settings.
+    Settings,
+    /// Show the speaker's model, firmware version, serial number and network details.
+    Info {
+        /// Print the details as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Have the speaker look for a firmware update.
+    CheckUpdate,
+    /// Install a firmware update, if there is one. The menubar and the TUI show its progress.
+    Update,
+    /// Rename the speaker, as shown by qaf and the KEF app.
+    Name { name: String },
+    /// Go into standby after 20 or 60 minutes without a signal, or never.
+    Standby { mode: StandbyMode },
+    /// Play a tone when powering on: `on` or `off`.
+    StartupTone {
+        #[arg(value_parser = parse_switch)]
+        state: bool,
+    },
+    /// Light the front LED in standby: `on` or `off`.
+    StandbyLed {
+        #[arg(value_parser = parse_switch)]
+        state: bool,
+    },
+    /// Ignore touches on the top panel: `on` or `off`.
+    PanelLock {
+        #[arg(value_parser = parse_switch)]
+        state: bool,
+    },
+    /// Power on when a signal arrives on an input: `on` or `off`.
+    AutoWake {
+        #[arg(value_parser = parse_switch)]
+        state: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ScheduleCommand {
+    /// Show all schedules.
+    List,
+    /// Add a schedule, e.g. `qaf schedule add 19:00 --days weekdays off`.
+    Add {
+        /// Local time of day, as HH:MM.
+        #[arg(value_parser = parse_time)]
+        time: NaiveTime,
+        /// Days to run on: `daily`, `weekdays`, `weekends`, names like `mon,wed` or ranges like
+        /// `mon-fri`.
+        #[arg(long, default_value = "daily")]
+        days: String,
+        /// What to do, in order: `on`, `off`, `input=<input>` or `volume=<0-100>`.
+        #[arg(required = true)]
+        actions: Vec<Action>,
+    },
+    /// Remove a schedule by its id, as shown by `list`.
+    Remove { id: u32 },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AlarmCommand {
+    /// Show all alarms.
+    List,
+    /// Add an alarm, e.g. `qaf alarm add 07:00 --days weekdays --input wifi --to 25`.
+    Add {
+        /// Local time of day, as HH:MM.
+        #[arg(value_parser = parse_time)]
+        time: NaiveTime,
+        /// Days to go off on, as for schedules.
+        #[arg(long, default_value = "daily")]
+        days: String,
+        /// Input to switch to; stays on the current one if not given.
+        #[arg(long, value_parser = parse_input)]
+        input: Option<InputSource>,
+        /// Volume to start at.
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(i32).range(0..=100))]
+        from: i32,
+        /// Volume to end up at.
+        #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(i32).range(0..=100))]
+        to: i32,
+        /// Minutes to take getting from one to the other.
+        #[arg(long, default_value_t = 10)]
+        ramp: u64,
+    },
+    /// Remove an alarm by its id, as shown by `list`.
+    Remove { id: u32 },
+}
+
+fn parse_favourite(s: &str) -> Result<FavouriteId, String> {
+    match s.parse() {
+        Ok(0) => Err("favourites are numbered from 1".to_string()),
+        Ok(index) => Ok(FavouriteId::Index(index)),
+        Err(_) => Ok(FavouriteId::Name(s.to_string())),
+    }
+}
+
+fn parse_url(s: &str) -> Result<String, String> {
+    match reqwest::Url::parse(s) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(s.to_string()),
+        Ok(_) => Err("the speaker only plays http and https URLs".to_string()),
+        Err(e) => Err(format!("not a URL: {e}")),
+    }
+}
+
+fn parse_input(s: &str) -> Result<InputSource, String> {
+    InputSource::from_kef_source(s)
+        .ok_or_else(|| format!("unknown input {s:?}; use usb, wifi, bluetooth, optical or tv"))
+}
+
+fn parse_json(s: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(s).map_err(|e| format!("not valid JSON: {e}"))
+}
+
+fn parse_switch(s: &str) -> Result<bool, String> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("{s:?} is not on or off")),
+    }
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s, "%H:%M").map_err(|_| format!("{s:?} is not a time like 19:00"))
+}
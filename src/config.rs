@@ -0,0 +1,416 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::InputSource;
+
+use chrono::NaiveTime;
+use serde::{Deserialize, Deserializer};
+use tokio::sync::watch;
+use tracing::{debug, error, info};
+
+// How often the config file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// User settings, read from `config.toml` in the platform config directory
+/// (`~/Library/Application Support/qaf` on macOS). Every field is optional.
+///
+/// ```toml
+/// speaker = "Office"
+/// poll_interval_secs = 30
+/// default_input = "wifi"
+/// enabled_inputs = ["usb", "wifi", "optical"]
+///
+/// [labels]
+/// optical = "Apple TV"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Name or IP address of the speaker to use when there is more than one on the network.
+    pub speaker: Option<String>,
+    /// Seconds between speaker status polls.
+    pub poll_interval_secs: u64,
+    /// Milliseconds between menubar refreshes.
+    pub ui_refresh_ms: u64,
+    /// Milliseconds to wait for the speaker status when the UI starts.
+    pub status_timeout_ms: u64,
+    /// Milliseconds to wait for a connection to the speaker.
+    pub connect_timeout_ms: u64,
+    /// Milliseconds to wait for the speaker to answer a request.
+    pub request_timeout_ms: u64,
+    /// Milliseconds to wait for the speaker to come out of standby.
+    pub power_on_timeout_ms: u64,
+    /// Times a failed request is retried.
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubling for each one after it up to 5 seconds.
+    pub retry_backoff_ms: u64,
+    /// Input to switch to whenever the speaker is powered on.
+    pub default_input: Option<InputSource>,
+    /// Inputs offered in the menubar and TUI, in order. Inputs left out are hidden.
+    pub enabled_inputs: Vec<InputSource>,
+    /// Display names for inputs, replacing the built-in names in every frontend.
+    pub labels: BTreeMap<InputSource, String>,
+    /// Fade the volume out over the last minute of the sleep timer.
+    pub sleep_fade: bool,
+    /// Minutes of silence after which the speaker is put in standby. Unset to never do that.
+    pub idle_standby_mins: Option<u64>,
+    /// Inputs that never go to standby for being idle, e.g. the TV.
+    pub idle_standby_exempt: Vec<InputSource>,
+    /// Switching input restores the volume last used on it.
+    pub restore_volume: bool,
+    pub volume_limit: VolumeLimit,
+}
+
+/// Volume ceilings enforced by qaf. The lowest one that applies wins.
+///
+/// ```toml
+/// [volume_limit]
+/// max = 70
+/// inputs = { tv = 50 }
+/// hours = [{ from = "21:00", to = "07:00", max = 30 }]
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeLimit {
+    /// Ceiling at all times.
+    pub max: Option<i32>,
+    /// Ceilings for particular inputs.
+    pub inputs: BTreeMap<InputSource, i32>,
+    /// Ceilings for times of day.
+    pub hours: Vec<HoursLimit>,
+    /// Also set the speaker's own maximum volume, so the limit holds for the remote and the KEF app
+    /// straight away rather than being corrected by qaf afterwards.
+    pub sync_speaker: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoursLimit {
+    #[serde(deserialize_with = "time_of_day")]
+    pub from: NaiveTime,
+    /// End of the period; earlier than `from` for periods that run past midnight.
+    #[serde(deserialize_with = "time_of_day")]
+    pub to: NaiveTime,
+    pub max: i32,
+}
+
+impl HoursLimit {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+}
+
+impl VolumeLimit {
+    pub fn is_active(&self) -> bool {
+        self.max.is_some() || !self.inputs.is_empty() || !self.hours.is_empty()
+    }
+
+    /// The highest volume allowed on `input` at `time`, if anything limits it.
+    pub fn ceiling(&self, input: Option<InputSource>, time: NaiveTime) -> Option<i32> {
+        let by_input = input.and_then(|input| self.inputs.get(&input).copied());
+        let by_hours = self
+            .hours
+            .iter()
+            .filter(|hours| hours.contains(time))
+            .map(|hours| hours.max);
+        self.max.into_iter().chain(by_input).chain(by_hours).min()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let maxes = self
+            .max
+            .iter()
+            .chain(self.inputs.values())
+            .chain(self.hours.iter().map(|hours| &hours.max));
+        for &max in maxes {
+            if !(0..=100).contains(&max) {
+                return Err(format!("volume limit {max} is not between 0 and 100"));
+            }
+        }
+        if let Some(hours) = self.hours.iter().find(|hours| hours.from == hours.to) {
+            return Err(format!(
+                "volume limit hours start and end at the same time ({})",
+                hours.from.format("%H:%M")
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn time_of_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M")
+        .map_err(|_| serde::de::Error::custom(format!("{s:?} is not a time like 21:00")))
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            speaker: None,
+            poll_interval_secs: 30,
+            ui_refresh_ms: 500,
+            status_timeout_ms: 2000,
+            connect_timeout_ms: 2000,
+            request_timeout_ms: 5000,
+            power_on_timeout_ms: 10_000,
+            retries: 3,
+            retry_backoff_ms: 250,
+            default_input: None,
+            enabled_inputs: InputSource::ALL.to_vec(),
+            labels: BTreeMap::new(),
+            sleep_fade: true,
+            idle_standby_mins: None,
+            idle_standby_exempt: Vec::new(),
+            restore_volume: true,
+            volume_limit: VolumeLimit::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Default location of the config file.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("qaf").join("config.toml"))
+    }
+
+    /// Read and validate the config at `path`. A missing file gives the defaults.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No config file at {}, using defaults", path.display());
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let config: Config = toml::from_str(&text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.poll_interval_secs == 0 {
+            return Err("poll_interval_secs must be at least 1".to_string());
+        }
+        if !(50..=10_000).contains(&self.ui_refresh_ms) {
+            return Err("ui_refresh_ms must be between 50 and 10000".to_string());
+        }
+        if self.status_timeout_ms == 0 {
+            return Err("status_timeout_ms must be at least 1".to_string());
+        }
+        if self.connect_timeout_ms == 0 || self.request_timeout_ms == 0 {
+            return Err("connect_timeout_ms and request_timeout_ms must be at least 1".to_string());
+        }
+        if !(1..=60_000).contains(&self.power_on_timeout_ms) {
+            return Err("power_on_timeout_ms must be between 1 and 60000".to_string());
+        }
+        if self.retries > 10 {
+            return Err("retries must be at most 10".to_string());
+        }
+        if self.retry_backoff_ms > 5000 {
+            return Err("retry_backoff_ms must be at most 5000".to_string());
+        }
+        if self.enabled_inputs.is_empty() {
+            return Err("enabled_inputs must list at least one input".to_string());
+        }
+        for (i, input) in self.enabled_inputs.iter().enumerate() {
+            if self.enabled_inputs[..i].contains(input) {
+                return Err(format!("enabled_inputs lists {input:?} twice"));
+            }
+        }
+        if let Some(input) = self.default_input
+            && !self.enabled_inputs.contains(&input)
+        {
+            return Err(format!("default_input {input:?} is not in enabled_inputs"));
+        }
+        if self.idle_standby_mins == Some(0) {
+            return Err("idle_standby_mins must be at least 1".to_string());
+        }
+        for (input, label) in &self.labels {
+            if label.trim().is_empty() {
+                return Err(format!("label for {input:?} is empty"));
+            }
+        }
+        self.volume_limit.validate()
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+
+    pub fn ui_refresh(&self) -> Duration {
+        Duration::from_millis(self.ui_refresh_ms)
+    }
+
+    pub fn status_timeout(&self) -> Duration {
+        Duration::from_millis(self.status_timeout_ms)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+
+    pub fn power_on_timeout(&self) -> Duration {
+        Duration::from_millis(self.power_on_timeout_ms)
+    }
+
+    pub fn retry_backoff(&self) -> Duration {
+        Duration::from_millis(self.retry_backoff_ms)
+    }
+
+    pub fn idle_standby(&self) -> Option<Duration> {
+        self.idle_standby_mins
+            .map(|mins| Duration::from_secs(mins * 60))
+    }
+
+    /// The name to show for `input`.
+    pub fn label(&self, input: InputSource) -> &str {
+        self.labels
+            .get(&input)
+            .map(String::as_str)
+            .unwrap_or(input.title())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Watch the config file and publish every valid edit. Invalid edits are logged and ignored, so
+/// the last good config stays in effect.
+pub async fn reload(path: PathBuf, tx: watch::Sender<Config>) {
+    let mut last_modified = modified(&path);
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let current = modified(&path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        match Config::load(&path) {
+            Ok(config) => {
+                if tx.send_if_modified(|old| {
+                    let changed = *old != config;
+                    *old = config;
+                    changed
+                }) {
+                    info!("Reloaded config from {}", path.display());
+                }
+            }
+            Err(e) => error!("Ignoring invalid config {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(toml: &str) -> VolumeLimit {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    fn hours(from: &str, to: &str) -> HoursLimit {
+        HoursLimit {
+            from: time(from),
+            to: time(to),
+            max: 30,
+        }
+    }
+
+    #[test]
+    fn hours_include_the_start_but_not_the_end() {
+        let evening = hours("18:00", "21:00");
+        assert!(!evening.contains(time("17:59")));
+        assert!(evening.contains(time("18:00")));
+        assert!(evening.contains(time("20:59")));
+        assert!(!evening.contains(time("21:00")));
+    }
+
+    #[test]
+    fn hours_can_run_past_midnight() {
+        let night = hours("21:00", "07:00");
+        assert!(night.contains(time("21:00")));
+        assert!(night.contains(time("00:00")));
+        assert!(night.contains(time("06:59")));
+        assert!(!night.contains(time("07:00")));
+        assert!(!night.contains(time("12:00")));
+        assert!(!night.contains(time("20:59")));
+    }
+
+    #[test]
+    fn lowest_ceiling_wins() {
+        let limit = limit(
+            r#"
+            max = 70
+            inputs = { tv = 50, wifi = 80 }
+            hours = [{ from = "21:00", to = "07:00", max = 30 }, { from = "22:00", to = "23:00", max = 40 }]
+            "#,
+        );
+        assert_eq!(limit.ceiling(None, time("12:00")), Some(70));
+        assert_eq!(
+            limit.ceiling(Some(InputSource::WiFi), time("12:00")),
+            Some(70)
+        );
+        assert_eq!(
+            limit.ceiling(Some(InputSource::Tv), time("12:00")),
+            Some(50)
+        );
+        assert_eq!(
+            limit.ceiling(Some(InputSource::Tv), time("22:30")),
+            Some(30)
+        );
+        assert_eq!(limit.ceiling(None, time("06:00")), Some(30));
+    }
+
+    #[test]
+    fn no_limits_no_ceiling() {
+        let limit = VolumeLimit::default();
+        assert!(!limit.is_active());
+        assert_eq!(limit.ceiling(Some(InputSource::Tv), time("22:00")), None);
+
+        let tv_only = self::limit("inputs = { tv = 50 }");
+        assert!(tv_only.is_active());
+        assert_eq!(tv_only.ceiling(Some(InputSource::USB), time("22:00")), None);
+        assert_eq!(tv_only.ceiling(None, time("22:00")), None);
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        assert!(limit("max = 100").validate().is_ok());
+        assert!(limit("max = 101").validate().is_err());
+        assert!(limit("inputs = { tv = -1 }").validate().is_err());
+        assert!(
+            limit(r#"hours = [{ from = "21:00", to = "07:00", max = 120 }]"#)
+                .validate()
+                .is_err()
+        );
+        assert!(
+            limit(r#"hours = [{ from = "21:00", to = "21:00", max = 30 }]"#)
+                .validate()
+                .is_err()
+        );
+        assert!(
+            toml::from_str::<VolumeLimit>(r#"hours = [{ from = "9pm", to = "07:00", max = 30 }]"#)
+                .is_err()
+        );
+    }
+}
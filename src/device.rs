@@ -0,0 +1,125 @@
+use crate::{DeviceSetting, FirmwareUpdate, SpeakerCommand, StandbyMode, cli::DeviceCommand};
+
+use tokio::sync::{mpsc, oneshot};
+
+/// Handle `qaf device …`: show the speaker's device settings, or change one of them.
+/// `named_in_config` says whether the config picks the speaker by its current name, which a
+/// rename leaves behind.
+pub async fn cli(
+    command: DeviceCommand,
+    named_in_config: bool,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let setting = match command {
+        DeviceCommand::Settings => return settings(speaker_tx).await,
+        DeviceCommand::Info { json } => return info(json, speaker_tx).await,
+        DeviceCommand::CheckUpdate => {
+            let (reply, response) = oneshot::channel();
+            speaker_tx.send(SpeakerCommand::CheckFirmwareUpdate(reply))?;
+            match response.await?? {
+                Some(FirmwareUpdate::Available { version }) => println!(
+                    "Firmware {}is available; install it with `qaf device update`",
+                    version.map_or_else(String::new, |v| format!("{v} "))
+                ),
+                Some(FirmwareUpdate::Running { progress }) => {
+                    println!("Firmware update under way ({progress}%)");
+                }
+                None => println!("Firmware is up to date"),
+            }
+            return Ok(());
+        }
+        DeviceCommand::Update => {
+            let (reply, response) = oneshot::channel();
+            speaker_tx.send(SpeakerCommand::StartFirmwareUpdate(reply))?;
+            response.await??;
+            println!("Firmware update started; the speaker restarts when it's done");
+            return Ok(());
+        }
+        DeviceCommand::Name { name } => {
+            let (reply, response) = oneshot::channel();
+            speaker_tx.send(SpeakerCommand::SetDeviceName {
+                name: name.clone(),
+                reply,
+            })?;
+            response.await??;
+            if named_in_config {
+                println!("The config still names the old one: change `speaker` to {name:?}");
+            }
+            return Ok(());
+        }
+        DeviceCommand::Standby { mode } => DeviceSetting::StandbyMode(mode),
+        DeviceCommand::StartupTone { state } => DeviceSetting::StartupTone(state),
+        DeviceCommand::StandbyLed { state } => DeviceSetting::StandbyLed(state),
+        DeviceCommand::PanelLock { state } => DeviceSetting::PanelLock(state),
+        DeviceCommand::AutoWake { state } => DeviceSetting::AutoWake(state),
+    };
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::SetDeviceSetting { setting, reply })?;
+    response.await??;
+    Ok(())
+}
+
+async fn info(
+    json: bool,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::GetDeviceInfo(reply))?;
+    let info = response.await??;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    let unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+    let address = match info.dhcp {
+        Some(true) => format!("{} (DHCP)", info.ip_address),
+        Some(false) => format!("{} (static)", info.ip_address),
+        None => info.ip_address.clone(),
+    };
+    let network = match (&info.interface, info.signal) {
+        (Some(interface), Some(signal)) => format!("{interface}, signal {signal}"),
+        (interface, _) => unknown(interface),
+    };
+    println!("Name           {}", info.name);
+    println!("Model          {}", info.model);
+    println!("Firmware       {}", unknown(&info.firmware));
+    println!("Serial number  {}", unknown(&info.serial));
+    println!("MAC address    {}", unknown(&info.mac_address));
+    println!("Network        {network}");
+    println!("IP address     {address}");
+    println!("Netmask        {}", unknown(&info.netmask));
+    println!("Gateway        {}", unknown(&info.gateway));
+    if info.dns.is_empty() {
+        println!("DNS            -");
+    } else {
+        println!("DNS            {}", info.dns.join(", "));
+    }
+    Ok(())
+}
+
+async fn settings(
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::GetDeviceSettings(reply))?;
+    let settings = response.await??;
+
+    let switch = |state: Option<bool>| match state {
+        Some(true) => "on",
+        Some(false) => "off",
+        None => "-",
+    };
+    let standby = match settings.standby_mode {
+        Some(StandbyMode::Minutes20) => "after 20 minutes",
+        Some(StandbyMode::Minutes60) => "after 60 minutes",
+        Some(StandbyMode::Never) => "never",
+        None => "-",
+    };
+    println!("Auto-standby   {standby}");
+    println!("Startup tone   {}", switch(settings.startup_tone));
+    println!("Standby LED    {}", switch(settings.standby_led));
+    println!("Panel lock     {}", switch(settings.panel_lock));
+    println!("Auto-wake      {}", switch(settings.auto_wake));
+    Ok(())
+}
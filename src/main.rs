@@ -1,29 +1,135 @@
 #![allow(unsafe_op_in_unsafe_fn)]
 
-use std::time::Duration;
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
 
-use objc2_foundation::NSString;
-use objc2_foundation::ns_string;
-use tracing::{info, trace};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, trace};
 
 // Channel for communication between UI and speaker controller
+use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
 
+use config::Config;
+
+mod alarm;
+mod api;
+mod backup;
+mod cli;
+mod config;
+mod device;
 mod menubar;
+mod play;
+mod scheduler;
 mod speaker;
+mod store;
+mod tui;
+mod watchdog;
 
 // Speaker discovery and control commands
 #[derive(Debug)]
 pub enum SpeakerCommand {
     SetInput(InputSource),
+    SetVolume(i32),
     GetStatus(oneshot::Sender<SpeakerStatus>),
     PowerOn,
     PowerOff,
+    /// Put the speaker in standby after the given time, replacing any running sleep timer.
+    SleepTimer(Duration),
+    CancelSleepTimer,
+    /// Change the volume gradually. Runs in the background, and any other volume change
+    /// (including another fade) cancels it.
+    FadeVolume {
+        target: i32,
+        duration: Duration,
+        curve: FadeCurve,
+    },
+    /// Power on and ramp the volume up, e.g. for an alarm.
+    Wake(WakeUp),
+    /// Read a setting by its API path (`value: None`), or write it as typed JSON
+    /// (`{"type": "i32_", "i32_": 30}`), for settings qaf doesn't model. Answers with the value
+    /// read or the speaker's reply to the write.
+    Raw {
+        path: String,
+        value: Option<serde_json::Value>,
+        reply: oneshot::Sender<Result<serde_json::Value, String>>,
+    },
+    /// Walk the settings tree under a path, listing every setting found.
+    Browse {
+        path: String,
+        reply: oneshot::Sender<Result<Vec<Setting>, String>>,
+    },
+    /// List the favourites stored on the speaker.
+    GetFavourites(oneshot::Sender<Result<Vec<Favourite>, String>>),
+    /// Play a favourite, switching to WiFi first if needed. Answers with its title.
+    PlayFavourite {
+        favourite: FavouriteId,
+        reply: oneshot::Sender<Result<String, String>>,
+    },
+    /// Play an HTTP audio stream or internet radio URL on the speaker, switching to WiFi first if
+    /// needed. `title` is what the speaker shows while it plays.
+    PlayUrl {
+        url: String,
+        title: String,
+        reply: oneshot::Sender<Result<(), String>>,
+    },
+    /// Have the speaker look for a firmware update, answering with what it found.
+    CheckFirmwareUpdate(oneshot::Sender<Result<Option<FirmwareUpdate>, String>>),
+    /// Start installing a firmware update. Until it's done, commands that change the speaker are
+    /// refused; progress shows up in the status.
+    StartFirmwareUpdate(oneshot::Sender<Result<(), String>>),
+    /// Identify the speaker: firmware, serial number and network details.
+    GetDeviceInfo(oneshot::Sender<Result<DeviceInfo, String>>),
+    /// Read the speaker's own device settings.
+    GetDeviceSettings(oneshot::Sender<Result<DeviceSettings, String>>),
+    /// Rename the speaker. Answers once the speaker has announced its new name on the network,
+    /// or given up waiting for it.
+    SetDeviceName {
+        name: String,
+        reply: oneshot::Sender<Result<(), String>>,
+    },
+    /// Change one of the speaker's device settings.
+    SetDeviceSetting {
+        setting: DeviceSetting,
+        reply: oneshot::Sender<Result<(), String>>,
+    },
     PollUpdate(SpeakerStatus),
 }
 
+/// How a volume fade progresses over its duration.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    /// Slow at first, then faster.
+    EaseIn,
+    /// Fast at first, then slower.
+    EaseOut,
+}
+
+impl FadeCurve {
+    // How far along the volume change is, `progress` of the way through the fade
+    fn apply(self, progress: f64) -> f64 {
+        match self {
+            FadeCurve::Linear => progress,
+            FadeCurve::EaseIn => progress * progress,
+            FadeCurve::EaseOut => 1.0 - (1.0 - progress) * (1.0 - progress),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
+pub struct WakeUp {
+    pub input: Option<InputSource>,
+    pub from: i32,
+    pub to: i32,
+    pub over: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpeakerInfo {
     pub address: String,
     pub port: u16,
@@ -36,9 +142,158 @@ pub struct SpeakerInfo {
 pub struct SpeakerStatus {
     pub power: String, // "standby" or "powerOn"
     pub source: Option<InputSource>,
+    pub volume: Option<i32>,
+    pub now_playing: Option<NowPlaying>,
+    pub sleep_timer: Option<Duration>, // time left before the sleep timer powers off
+    pub connectivity: Connectivity,
+    pub update: Option<FirmwareUpdate>,
+}
+
+/// A firmware update the speaker knows about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirmwareUpdate {
+    /// A newer firmware can be installed.
+    Available { version: Option<String> },
+    /// Downloading or installing, `progress` percent of the way through.
+    Running { progress: u8 },
+}
+
+/// How well the speaker has been answering, so frontends can tell "offline" from "standby".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Connectivity {
+    /// When the speaker last answered a request.
+    pub last_contact: Option<Instant>,
+    /// Requests that have failed in a row (after retries) since then.
+    pub failures: u32,
+    /// How long the last answered request took.
+    pub latency: Option<Duration>,
+}
+
+impl Connectivity {
+    pub fn is_online(&self) -> bool {
+        self.last_contact.is_some() && self.failures == 0
+    }
+}
+
+/// A setting found by browsing the speaker's settings tree.
+#[derive(Debug, Clone, Serialize)]
+pub struct Setting {
+    pub path: String,
+    /// The value's type, like `i32_` or `kefPhysicalSource`, or what the row says it is when it
+    /// has no value.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The value in its typed form, as `qaf api get` prints it.
+    pub value: Option<serde_json::Value>,
+}
+
+/// A favourite stored on the speaker, like an internet radio station.
+#[derive(Debug, Clone, Serialize)]
+pub struct Favourite {
+    /// Position in the speaker's list, from 1.
+    pub index: usize,
+    pub title: String,
+    // The row as the speaker lists it, which is what it needs to play it
+    #[serde(skip)]
+    roles: serde_json::Value,
+}
+
+/// Which favourite to play: its position in the list, or (part of) its title.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FavouriteId {
+    Index(usize),
+    Name(String),
+}
+
+impl std::fmt::Display for FavouriteId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FavouriteId::Index(index) => write!(f, "{index}"),
+            FavouriteId::Name(name) => write!(f, "{name:?}"),
+        }
+    }
+}
+
+/// What a speaker is and how it's connected, for keeping an inventory. Name, model and address
+/// are as announced on mDNS; the rest is `None` where the speaker doesn't report it.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub model: String,
+    pub firmware: Option<String>,
+    pub serial: Option<String>,
+    pub mac_address: Option<String>,
+    /// `wired` or `wireless`.
+    pub interface: Option<String>,
+    /// Wi-Fi signal strength, as the speaker reports it.
+    pub signal: Option<i64>,
+    pub ip_address: String,
+    pub netmask: Option<String>,
+    pub gateway: Option<String>,
+    pub dns: Vec<String>,
+    /// Whether the address came from DHCP rather than being set by hand.
+    pub dhcp: Option<bool>,
+}
+
+/// The speaker's device settings, each `None` where the firmware doesn't have it.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceSettings {
+    pub standby_mode: Option<StandbyMode>,
+    pub startup_tone: Option<bool>,
+    pub standby_led: Option<bool>,
+    pub panel_lock: Option<bool>,
+    /// Power on when a signal arrives on an input.
+    pub auto_wake: Option<bool>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceSetting {
+    StandbyMode(StandbyMode),
+    StartupTone(bool),
+    StandbyLed(bool),
+    PanelLock(bool),
+    AutoWake(bool),
+}
+
+/// How long the speaker waits without a signal before going into standby by itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StandbyMode {
+    #[value(name = "20m")]
+    Minutes20,
+    #[value(name = "60m")]
+    Minutes60,
+    Never,
+}
+
+impl StandbyMode {
+    fn to_kef_mode(self) -> &'static str {
+        match self {
+            StandbyMode::Minutes20 => "standby_20mins",
+            StandbyMode::Minutes60 => "standby_60mins",
+            StandbyMode::Never => "standby_none",
+        }
+    }
+
+    fn from_kef_mode(s: &str) -> Option<Self> {
+        match s {
+            "standby_20mins" => Some(StandbyMode::Minutes20),
+            "standby_60mins" => Some(StandbyMode::Minutes60),
+            "standby_none" => Some(StandbyMode::Never),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NowPlaying {
+    pub state: String, // "playing", "paused" or "stopped"
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum InputSource {
     USB,
     WiFi,
@@ -48,6 +303,24 @@ pub enum InputSource {
 }
 
 impl InputSource {
+    pub const ALL: [InputSource; 5] = [
+        InputSource::USB,
+        InputSource::WiFi,
+        InputSource::Bluetooth,
+        InputSource::Optical,
+        InputSource::Tv,
+    ];
+
+    pub fn title(self) -> &'static str {
+        match self {
+            InputSource::USB => "USB",
+            InputSource::WiFi => "WiFi",
+            InputSource::Bluetooth => "Bluetooth",
+            InputSource::Optical => "Optical",
+            InputSource::Tv => "TV",
+        }
+    }
+
     fn to_kef_source(self) -> &'static str {
         match self {
             InputSource::USB => "usb",
@@ -58,20 +331,15 @@ impl InputSource {
         }
     }
 
-    fn from_ns_string(s: &NSString) -> Option<Self> {
-        if s == ns_string!("USB") {
-            Some(InputSource::USB)
-        } else if s == ns_string!("WiFi") {
-            Some(InputSource::WiFi)
-        } else if s == ns_string!("Bluetooth") {
-            Some(InputSource::Bluetooth)
-        } else if s == ns_string!("Optical") {
-            Some(InputSource::Optical)
-        } else if s == ns_string!("Tv") {
-            Some(InputSource::Tv)
-        } else {
-            None
-        }
+    // Stable identifier for menu items, independent of the label shown
+    fn tag(self) -> isize {
+        InputSource::ALL.iter().position(|&i| i == self).unwrap() as isize + 1
+    }
+
+    fn from_tag(tag: isize) -> Option<Self> {
+        InputSource::ALL
+            .get(usize::try_from(tag).ok()?.checked_sub(1)?)
+            .copied()
     }
 
     fn from_kef_source(s: &str) -> Option<Self> {
@@ -86,37 +354,182 @@ impl InputSource {
     }
 }
 
+fn exit_on_error(result: Result<(), Box<dyn std::error::Error>>) {
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+// Connect to the speaker and run `f` against a controller of its own, for commands that do one
+// thing and exit
+fn run_once<F, Fut>(config: Config, f: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(mpsc::UnboundedSender<SpeakerCommand>, SpeakerInfo) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn std::error::Error>>>,
+{
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let preferred = config.speaker.clone();
+        let info = speaker::SpeakerController::connect(preferred)
+            .await
+            .ok_or("no speaker found")?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (_config_tx, config_rx) = watch::channel(config);
+        let (info_tx, _info_rx) = watch::channel(Some(info.clone()));
+        let controller = speaker::SpeakerController::new(info.clone(), info_tx, config_rx).run(rx);
+
+        tokio::select! {
+            result = f(tx, info) => result,
+            () = controller => Err("speaker controller stopped".into()),
+        }
+    })
+}
+
 fn main() {
-    // Initialize tracing first
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+    let cli = cli::Cli::parse();
+
+    // Initialize tracing first. Logs go to stderr so they stay out of the way of anything printed
+    // on stdout. The TUI draws on the terminal too, so unless stderr is redirected away from it,
+    // its logs go to a file instead.
+    let log_file = match cli.command {
+        Some(cli::Command::Tui) if std::io::stderr().is_terminal() => Some(tui::log_file()),
+        _ => None,
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    match log_file {
+        Some(file) => subscriber
+            .with_ansi(false)
+            .with_writer(std::sync::Mutex::new(file))
+            .init(),
+        None => subscriber.with_writer(std::io::stderr).init(),
+    }
 
-    info!("Starting qaf menubar app");
+    info!("Starting qaf");
+
+    // Editing schedules and alarms doesn't involve the speaker at all
+    let frontend = match cli.command {
+        Some(cli::Command::Schedule { command }) => return exit_on_error(scheduler::cli(command)),
+        Some(cli::Command::Alarm { command }) => return exit_on_error(alarm::cli(command)),
+        frontend => frontend,
+    };
+
+    let config_path = match cli.config.clone().or_else(Config::default_path) {
+        Some(path) => path,
+        None => {
+            error!("Could not determine the config directory; pass --config");
+            std::process::exit(1);
+        }
+    };
+    let config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid config {}: {}", config_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    info!("Using config {}", config_path.display());
+
+    // One-off commands that talk to the speaker
+    let frontend = match frontend {
+        Some(cli::Command::Api { command }) => {
+            return exit_on_error(run_once(config, |tx, _| api::cli(command, tx)));
+        }
+        Some(cli::Command::Device { command }) => {
+            let configured = config.speaker.clone();
+            return exit_on_error(run_once(config, |tx, info| {
+                let named_in_config = configured == Some(info.name);
+                device::cli(command, named_in_config, tx)
+            }));
+        }
+        Some(cli::Command::Favourites { json }) => {
+            return exit_on_error(run_once(config, |tx, _| play::favourites(json, tx)));
+        }
+        Some(cli::Command::Play { favourite }) => {
+            return exit_on_error(run_once(config, |tx, _| play::favourite(favourite, tx)));
+        }
+        Some(cli::Command::PlayUrl { url, title }) => {
+            return exit_on_error(run_once(config, |tx, _| play::url(url, title, tx)));
+        }
+        Some(cli::Command::Fade {
+            volume,
+            over,
+            curve,
+        }) => {
+            let duration = Duration::from_secs(over);
+            return exit_on_error(run_once(config, |tx, _| {
+                play::fade(volume, duration, curve, tx)
+            }));
+        }
+        Some(cli::Command::Backup) => {
+            let synced = config.volume_limit.sync_speaker;
+            return exit_on_error(run_once(config, |tx, info| {
+                backup::backup(synced, tx, info)
+            }));
+        }
+        Some(cli::Command::Restore {
+            file,
+            dry_run,
+            force,
+        }) => {
+            let synced = config.volume_limit.sync_speaker;
+            return exit_on_error(run_once(config, |tx, info| {
+                backup::restore(file, dry_run, force, synced, tx, info)
+            }));
+        }
+        frontend => frontend,
+    };
+
+    // The reload task gets the sender; everyone else gets a receiver and sees edits as they happen.
+    let (config_tx, config_rx) = watch::channel(config);
 
     // The macOS UI thread (main thread) gets the sender; the SpeakerController gets the receiver.
     // Used to communicate between the UI and the http API.
     let (tx, rx) = mpsc::unbounded_channel::<SpeakerCommand>();
     // Used to send GetStatus commands from the periodic polling task.
     let tx2 = tx.clone();
-    // Speaker status polling task gets the sender. The macOS UI gets the receiver.
-    // Used to keep the UI in sync with the state of the speaker.
-    let (poll_tx, poll_rx) = mpsc::unbounded_channel::<SpeakerStatus>();
+    // Used by the scheduler to run scheduled actions.
+    let scheduler_tx = tx.clone();
+    // Speaker status polling task gets the sender. The UI and the idle watchdog each get a
+    // receiver. Used to keep them in sync with the state of the speaker.
+    let (poll_tx, poll_rx) = broadcast::channel::<SpeakerStatus>(16);
+    let watchdog_rx = poll_tx.subscribe();
+    let watchdog_tx = tx.clone();
+    let watchdog_config = config_rx.clone();
+    // The speaker we're talking to, once it has been found. The UI starts right away and picks it
+    // up from here, rather than waiting for discovery.
+    let (info_tx, info_rx) = watch::channel::<Option<SpeakerInfo>>(None);
 
-    let speaker_info = speaker::SpeakerController::discover_speaker()
-        .expect("no speaker; do something better here");
-    let controller = speaker::SpeakerController::new(speaker_info, rx);
+    let controller_config = config_rx.clone();
+    let mut poll_config = config_rx.clone();
 
     // Spawn the async runtime in a separate thread
     std::thread::spawn(move || {
         let runtime = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
         runtime.block_on(async {
+            tokio::spawn(config::reload(config_path, config_tx));
+            tokio::spawn(scheduler::run(scheduler_tx));
+            tokio::spawn(watchdog::run(watchdog_rx, watchdog_tx, watchdog_config));
+
             // Start periodic polling task
             tokio::spawn(async move {
-                let mut interval = tokio::time::interval(Duration::from_secs(30));
+                let mut interval = tokio::time::interval(poll_config.borrow().poll_interval());
 
                 loop {
-                    interval.tick().await;
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        Ok(()) = poll_config.changed() => {
+                            let period = poll_config.borrow_and_update().poll_interval();
+                            if period != interval.period() {
+                                interval = tokio::time::interval_at(
+                                    tokio::time::Instant::now() + period,
+                                    period,
+                                );
+                            }
+                            continue;
+                        }
+                    }
                     let (status_tx, status_rx) = oneshot::channel();
                     let _ = tx2.send(SpeakerCommand::GetStatus(status_tx));
                     let status = status_rx
@@ -127,10 +540,37 @@ fn main() {
                 }
             });
 
-            controller.run().await;
+            let preferred = controller_config.borrow().speaker.clone();
+            // Without a speaker there's no controller, which the frontend notices and shuts down
+            let Some(speaker_info) = speaker::SpeakerController::connect(preferred).await else {
+                error!("No speaker found");
+                return;
+            };
+            let _ = info_tx.send(Some(speaker_info.clone()));
+
+            speaker::SpeakerController::new(speaker_info, info_tx, controller_config)
+                .run(rx)
+                .await;
         });
     });
 
     // Run the UI on the main thread
-    menubar::run(tx, poll_rx);
+    match frontend {
+        None => menubar::run(tx, poll_rx, config_rx),
+        Some(cli::Command::Tui) => tui::run(info_rx, tx, poll_rx, config_rx),
+        Some(
+            cli::Command::Schedule { .. }
+            | cli::Command::Alarm { .. }
+            | cli::Command::Api { .. }
+            | cli::Command::Device { .. }
+            | cli::Command::Favourites { .. }
+            | cli::Command::Play { .. }
+            | cli::Command::PlayUrl { .. }
+            | cli::Command::Fade { .. }
+            | cli::Command::Backup
+            | cli::Command::Restore { .. },
+        ) => {
+            unreachable!("handled before starting up")
+        }
+    }
 }
@@ -1,6 +1,10 @@
 use std::cell::{OnceCell, RefCell};
+use std::time::Duration;
 
-use crate::{InputSource, SpeakerCommand, SpeakerStatus};
+use crate::{
+    Favourite, FavouriteId, FirmwareUpdate, InputSource, SpeakerCommand, SpeakerStatus,
+    config::Config,
+};
 
 use objc2::{
     DeclaredClass, MainThreadMarker, MainThreadOnly, Message, define_class, msg_send, rc::Retained,
@@ -11,20 +15,34 @@ use objc2_app_kit::{
     NSStatusBar, NSStatusItem,
 };
 use objc2_foundation::{NSObject, NSObjectProtocol, NSString, NSTimeInterval, NSTimer};
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, info};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tracing::{debug, error, info};
+
+// Sleep timer lengths offered in the menu, in minutes
+const SLEEP_TIMER_MINUTES: [isize; 4] = [15, 30, 60, 90];
+
+type FavouritesReply = oneshot::Receiver<Result<Vec<Favourite>, String>>;
 
 // Ivars to store our app state
 #[derive(Debug)]
 pub struct AppDelegateIvars {
     status_item: OnceCell<Retained<NSStatusItem>>,
     menu: OnceCell<Retained<NSMenu>>,
+    input_items: RefCell<Vec<Retained<NSMenuItem>>>,
+    timer: RefCell<Option<Retained<NSTimer>>>,
     current_input: RefCell<Option<InputSource>>,
     power_item: OnceCell<Retained<NSMenuItem>>,
+    offline_item: OnceCell<Retained<NSMenuItem>>,
+    sleep_item: OnceCell<Retained<NSMenuItem>>,
+    favourites_item: OnceCell<Retained<NSMenuItem>>,
+    // Titles in the favourites submenu
+    favourites: RefCell<Vec<String>>,
+    // The speaker controller's answer to the last request for the favourites, while waiting
+    favourites_rx: RefCell<Option<FavouritesReply>>,
     speaker_powered: RefCell<bool>,
-    poll_rx: RefCell<UnboundedReceiver<SpeakerStatus>>,
+    poll_rx: RefCell<broadcast::Receiver<SpeakerStatus>>,
     speaker_tx: RefCell<mpsc::UnboundedSender<SpeakerCommand>>,
+    config: RefCell<watch::Receiver<Config>>,
 }
 
 // Create our app delegate class
@@ -56,7 +74,8 @@ define_class!(
             let menu = NSMenu::new(mtm);
 
             // Query speaker status first
-            let current_input = {
+            let (current_input, online) = {
+                let status_timeout = self.ivars().config.borrow().borrow().status_timeout();
                 let (status_tx, status_rx) = oneshot::channel();
                 let _ = self.ivars().speaker_tx.borrow().send(SpeakerCommand::GetStatus(status_tx));
 
@@ -65,7 +84,7 @@ define_class!(
                     let rt = tokio::runtime::Runtime::new().unwrap();
                     rt.block_on(async {
                         tokio::time::timeout(
-                            tokio::time::Duration::from_secs(2),
+                            status_timeout,
                             status_rx
                         ).await
                     })
@@ -73,11 +92,11 @@ define_class!(
                     Ok(Ok(Ok(status))) => {
                         info!("Speaker status on startup: {:?}", status);
                         *self.ivars().speaker_powered.borrow_mut() = status.power == "powerOn";
-                        status.source
+                        (status.source, status.connectivity.is_online())
                     }
                     _ => {
                         info!("Failed to get speaker status, defaulting to no selection");
-                        None
+                        (None, true)
                     }
                 }
             };
@@ -85,115 +104,98 @@ define_class!(
             // Update the stored current input
             *self.ivars().current_input.borrow_mut() = current_input;
 
-            // Create menu items
-            let usb_item = unsafe {
-                NSMenuItem::initWithTitle_action_keyEquivalent(
-                    NSMenuItem::alloc(mtm),
-                    &NSString::from_str("USB"),
-                    Some(objc2::sel!(menuItemClicked:)),
-                    &NSString::from_str(""),
-                )
-            };
-            unsafe {
-                usb_item.setTarget(Some(&self.retain()));
-                usb_item.setTag(1);
-                if current_input == Some(InputSource::USB) {
-                    let _: () = msg_send![&usb_item, setState: 1i64];
-                }
-            }
+            // Create the input source menu items
+            let config = self.ivars().config.borrow().borrow().clone();
+            self.add_input_items(&menu, &config);
 
-            let wifi_item = unsafe {
-                NSMenuItem::initWithTitle_action_keyEquivalent(
-                    NSMenuItem::alloc(mtm),
-                    &NSString::from_str("WiFi"),
-                    Some(objc2::sel!(menuItemClicked:)),
-                    &NSString::from_str(""),
-                )
-            };
-            unsafe {
-                wifi_item.setTarget(Some(&self.retain()));
-                wifi_item.setTag(2);
-                if current_input == Some(InputSource::WiFi) {
-                    let _: () = msg_send![&wifi_item, setState: 1i64];
-                }
-            }
+            // Add separator before power control
+            let separator1 = NSMenuItem::separatorItem(mtm);
+            menu.addItem(&separator1);
 
-            let bluetooth_item = unsafe {
+            // Shown instead of guessing at the power state while the speaker isn't answering
+            let offline_item = unsafe {
                 NSMenuItem::initWithTitle_action_keyEquivalent(
                     NSMenuItem::alloc(mtm),
-                    &NSString::from_str("Bluetooth"),
-                    Some(objc2::sel!(menuItemClicked:)),
+                    &NSString::from_str("Speaker offline"),
+                    None,
                     &NSString::from_str(""),
                 )
             };
             unsafe {
-                bluetooth_item.setTarget(Some(&self.retain()));
-                bluetooth_item.setTag(3);
-                if current_input == Some(InputSource::Bluetooth) {
-                    let _: () = msg_send![&bluetooth_item, setState: 1i64];
-                }
+                offline_item.setEnabled(false);
+                offline_item.setHidden(online);
             }
+            menu.addItem(&offline_item);
+            self.ivars().offline_item.set(offline_item).ok();
 
-            let optical_item = unsafe {
+            // Add power on/off item
+            let power_text = if *self.ivars().speaker_powered.borrow() {
+                "Power Off"
+            } else {
+                "Power On"
+            };
+            let power_item = unsafe {
                 NSMenuItem::initWithTitle_action_keyEquivalent(
                     NSMenuItem::alloc(mtm),
-                    &NSString::from_str("Optical"),
-                    Some(objc2::sel!(menuItemClicked:)),
+                    &NSString::from_str(power_text),
+                    Some(objc2::sel!(powerClicked:)),
                     &NSString::from_str(""),
                 )
             };
-            unsafe {
-                optical_item.setTarget(Some(&self.retain()));
-                optical_item.setTag(4);
-                if current_input == Some(InputSource::Optical) {
-                    let _: () = msg_send![&optical_item, setState: 1i64];
+            unsafe { power_item.setTarget(Some(&self.retain())) };
+            menu.addItem(&power_item);
+            self.ivars().power_item.set(power_item).ok();
+
+            // Add the sleep timer submenu; item tags are the timer length in minutes, 0 for off
+            let sleep_menu = NSMenu::new(mtm);
+            for minutes in SLEEP_TIMER_MINUTES.into_iter().chain([0]) {
+                if minutes == 0 {
+                    sleep_menu.addItem(&NSMenuItem::separatorItem(mtm));
+                }
+                let title = if minutes == 0 {
+                    "Off".to_string()
+                } else {
+                    format!("{minutes} minutes")
+                };
+                let item = unsafe {
+                    NSMenuItem::initWithTitle_action_keyEquivalent(
+                        NSMenuItem::alloc(mtm),
+                        &NSString::from_str(&title),
+                        Some(objc2::sel!(sleepTimerClicked:)),
+                        &NSString::from_str(""),
+                    )
+                };
+                unsafe {
+                    item.setTarget(Some(&self.retain()));
+                    item.setTag(minutes);
                 }
+                sleep_menu.addItem(&item);
             }
-
-            let tv_item = unsafe {
+            let sleep_item = unsafe {
                 NSMenuItem::initWithTitle_action_keyEquivalent(
                     NSMenuItem::alloc(mtm),
-                    &NSString::from_str("Tv"),
-                    Some(objc2::sel!(menuItemClicked:)),
+                    &NSString::from_str("Sleep Timer"),
+                    None,
                     &NSString::from_str(""),
                 )
             };
-            unsafe {
-                tv_item.setTarget(Some(&self.retain()));
-                tv_item.setTag(5);
-                if current_input == Some(InputSource::Tv) {
-                    let _: () = msg_send![&tv_item, setState: 1i64];
-                }
-            }
-
-            // Add items to menu
-            menu.addItem(&usb_item);
-            menu.addItem(&wifi_item);
-            menu.addItem(&bluetooth_item);
-            menu.addItem(&optical_item);
-            menu.addItem(&tv_item);
+            sleep_item.setSubmenu(Some(&sleep_menu));
+            menu.addItem(&sleep_item);
+            self.ivars().sleep_item.set(sleep_item).ok();
 
-            // Add separator before power control
-            let separator1 = NSMenuItem::separatorItem(mtm);
-            menu.addItem(&separator1);
-
-            // Add power on/off item
-            let power_text = if *self.ivars().speaker_powered.borrow() {
-                "Power Off"
-            } else {
-                "Power On"
-            };
-            let power_item = unsafe {
+            // Add the favourites submenu, hidden until the speaker has listed some
+            let favourites_item = unsafe {
                 NSMenuItem::initWithTitle_action_keyEquivalent(
                     NSMenuItem::alloc(mtm),
-                    &NSString::from_str(power_text),
-                    Some(objc2::sel!(powerClicked:)),
+                    &NSString::from_str("Favourites"),
+                    None,
                     &NSString::from_str(""),
                 )
             };
-            unsafe { power_item.setTarget(Some(&self.retain())) };
-            menu.addItem(&power_item);
-            self.ivars().power_item.set(power_item).ok();
+            unsafe { favourites_item.setHidden(true) };
+            menu.addItem(&favourites_item);
+            self.ivars().favourites_item.set(favourites_item).ok();
+            self.request_favourites();
 
             // Add separator before quit
             let separator2 = NSMenuItem::separatorItem(mtm);
@@ -232,21 +234,21 @@ define_class!(
             info!("Status bar item created");
 
             // Start timer to process poll updates
-            let _timer = unsafe {
-                NSTimer::scheduledTimerWithTimeInterval_target_selector_userInfo_repeats(
-                    0.5 as NSTimeInterval, // Check every half second for faster discovery feedback
-                    &self.retain(),
-                    sel!(processPollUpdates:),
-                    None,
-                    true,
-                )
-            };
+            self.schedule_timer(config.ui_refresh());
         }
 
     }
     impl AppDelegate {
         #[unsafe(method(processPollUpdates:))]
         fn process_poll_updates(&self, _timer: &NSTimer) {
+            if self.ivars().speaker_tx.borrow().is_closed() {
+                error!("Speaker controller stopped, exiting");
+                std::process::exit(1);
+            }
+            if self.ivars().config.borrow().has_changed().unwrap_or(false) {
+                self.apply_config();
+            }
+
             while let Ok(status) = self.ivars().poll_rx.borrow_mut().try_recv() {
                 debug!("Processing poll update: {:?}", status);
 
@@ -262,25 +264,32 @@ define_class!(
                     }
                 }
 
-                // Update menu checkmarks
-                if let Some(menu) = self.ivars().menu.get() {
-                    let item_count = unsafe { menu.numberOfItems() };
-                    for i in 0..item_count {
-                        if let Some(item) = unsafe { menu.itemAtIndex(i) } {
-                            let title = unsafe { item.title() };
-                            if let Some(input) = InputSource::from_ns_string(&title) {
-                                unsafe {
-                                    if status.source == Some(input) {
-                                        let _: () = msg_send![&item, setState: 1i64];
-                                    } else {
-                                        let _: () = msg_send![&item, setState: 0i64];
-                                    }
-                                }
-                            }
+                // Also shows firmware update progress, since the speaker can't be used meanwhile
+                if let Some(offline_item) = self.ivars().offline_item.get() {
+                    let (title, hidden) = match status.update {
+                        Some(FirmwareUpdate::Running { progress }) => {
+                            (format!("Updating firmware ({progress}%)"), false)
                         }
+                        _ => (
+                            "Speaker offline".to_string(),
+                            status.connectivity.is_online(),
+                        ),
+                    };
+                    unsafe {
+                        offline_item.setTitle(&NSString::from_str(&title));
+                        offline_item.setHidden(hidden);
                     }
                 }
+
+                // Update menu checkmarks
+                self.update_checkmarks(status.source);
+
+                self.update_sleep_timer(status.sleep_timer);
+
+                // Pick up favourites added or reordered in the KEF app
+                self.request_favourites();
             }
+            self.update_favourites();
         }
 
         #[unsafe(method(menuItemClicked:))]
@@ -288,26 +297,13 @@ define_class!(
             let title = unsafe { sender.title() };
             debug!("Menu item clicked: {}", title);
 
-            // Parse the input source
-            if let Some(input) = InputSource::from_ns_string(&title) {
+            // Input items are identified by their tag, not their (configurable) title
+            if let Some(input) = InputSource::from_tag(unsafe { sender.tag() }) {
                 // Update the current input
                 *self.ivars().current_input.borrow_mut() = Some(input);
 
                 // Update the menu item states
-                if let Some(menu) = self.ivars().menu.get() {
-                    let item_count = unsafe { menu.numberOfItems() };
-                    for i in 0..item_count {
-                        if let Some(item) = unsafe { menu.itemAtIndex(i) } {
-                            unsafe {
-                                if item.title() == title {
-                                    let _: () = msg_send![&item, setState: 1i64];
-                                } else {
-                                    let _: () = msg_send![&item, setState: 0i64];
-                                }
-                            }
-                        }
-                    }
-                }
+                self.update_checkmarks(Some(input));
 
                 // Send command to speaker controller
                 let _ = self.ivars().speaker_tx.borrow().send(SpeakerCommand::SetInput(input));
@@ -357,6 +353,34 @@ define_class!(
             }
         }
 
+        #[unsafe(method(sleepTimerClicked:))]
+        fn sleep_timer_clicked(&self, sender: &NSMenuItem) {
+            let minutes = unsafe { sender.tag() };
+            let duration = (minutes > 0).then(|| Duration::from_secs(minutes as u64 * 60));
+            info!("Sleep timer clicked: {:?}", duration);
+
+            let command = match duration {
+                Some(duration) => SpeakerCommand::SleepTimer(duration),
+                None => SpeakerCommand::CancelSleepTimer,
+            };
+            let _ = self.ivars().speaker_tx.borrow().send(command);
+            self.update_sleep_timer(duration);
+        }
+
+        #[unsafe(method(favouriteClicked:))]
+        fn favourite_clicked(&self, sender: &NSMenuItem) {
+            // By title, as the speaker's list may have been reordered since the menu was built
+            let title = unsafe { sender.title() }.to_string();
+            info!("Favourite clicked: {}", title);
+
+            // Nobody waits for the answer; the controller logs failures
+            let (reply, _) = oneshot::channel();
+            let _ = self.ivars().speaker_tx.borrow().send(SpeakerCommand::PlayFavourite {
+                favourite: FavouriteId::Name(title),
+                reply,
+            });
+        }
+
         #[unsafe(method(quitClicked:))]
         fn quit_clicked(&self, _sender: &NSMenuItem) {
             info!("Quit clicked - exiting application");
@@ -372,25 +396,191 @@ impl AppDelegate {
     pub fn new(
         mtm: MainThreadMarker,
         speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
-        poll_rx: mpsc::UnboundedReceiver<SpeakerStatus>,
+        poll_rx: broadcast::Receiver<SpeakerStatus>,
+        config: watch::Receiver<Config>,
     ) -> Retained<Self> {
         let this = Self::alloc(mtm);
         let this = this.set_ivars(AppDelegateIvars {
             status_item: OnceCell::new(),
             menu: OnceCell::new(),
+            input_items: RefCell::new(Vec::new()),
+            timer: RefCell::new(None),
             current_input: RefCell::new(None),
             power_item: OnceCell::new(),
+            offline_item: OnceCell::new(),
+            sleep_item: OnceCell::new(),
+            favourites_item: OnceCell::new(),
+            favourites: RefCell::new(Vec::new()),
+            favourites_rx: RefCell::new(None),
             speaker_powered: RefCell::new(false),
             poll_rx: RefCell::new(poll_rx),
             speaker_tx: RefCell::new(speaker_tx),
+            config: RefCell::new(config),
         });
         unsafe { msg_send![super(this), init] }
     }
+
+    // Create a menu item for each enabled input and insert them at the top of the menu
+    fn add_input_items(&self, menu: &NSMenu, config: &Config) {
+        let mtm = MainThreadMarker::from(self);
+        let current_input = *self.ivars().current_input.borrow();
+        let mut items = self.ivars().input_items.borrow_mut();
+
+        for (i, &input) in config.enabled_inputs.iter().enumerate() {
+            let item = unsafe {
+                NSMenuItem::initWithTitle_action_keyEquivalent(
+                    NSMenuItem::alloc(mtm),
+                    &NSString::from_str(config.label(input)),
+                    Some(objc2::sel!(menuItemClicked:)),
+                    &NSString::from_str(""),
+                )
+            };
+            unsafe {
+                item.setTarget(Some(&self.retain()));
+                item.setTag(input.tag());
+                if current_input == Some(input) {
+                    let _: () = msg_send![&item, setState: 1i64];
+                }
+                menu.insertItem_atIndex(&item, i as isize);
+            }
+            items.push(item);
+        }
+    }
+
+    // Check the item for `source`, if it's shown, and uncheck all other inputs
+    fn update_checkmarks(&self, source: Option<InputSource>) {
+        for item in self.ivars().input_items.borrow().iter() {
+            unsafe {
+                if source.is_some() && InputSource::from_tag(item.tag()) == source {
+                    let _: () = msg_send![item, setState: 1i64];
+                } else {
+                    let _: () = msg_send![item, setState: 0i64];
+                }
+            }
+        }
+    }
+
+    // Show the time left on the sleep timer in its menu item
+    fn update_sleep_timer(&self, remaining: Option<Duration>) {
+        if let Some(sleep_item) = self.ivars().sleep_item.get() {
+            let title = match remaining {
+                Some(left) => format!("Sleep Timer ({} min left)", left.as_secs().div_ceil(60)),
+                None => "Sleep Timer".to_string(),
+            };
+            unsafe { sleep_item.setTitle(&NSString::from_str(&title)) };
+        }
+    }
+
+    // Ask the speaker controller for the favourites, unless already waiting for them. The answer
+    // is picked up by `update_favourites`, so the main thread never waits for the speaker.
+    fn request_favourites(&self) {
+        let mut pending = self.ivars().favourites_rx.borrow_mut();
+        if pending.is_none() {
+            let (tx, rx) = oneshot::channel();
+            let _ = self
+                .ivars()
+                .speaker_tx
+                .borrow()
+                .send(SpeakerCommand::GetFavourites(tx));
+            *pending = Some(rx);
+        }
+    }
+
+    // Rebuild the favourites submenu if the speaker has answered with a different list
+    fn update_favourites(&self) {
+        let result = {
+            let mut pending = self.ivars().favourites_rx.borrow_mut();
+            let Some(rx) = pending.as_mut() else {
+                return;
+            };
+            match rx.try_recv() {
+                Ok(result) => {
+                    *pending = None;
+                    result
+                }
+                Err(oneshot::error::TryRecvError::Empty) => return,
+                Err(oneshot::error::TryRecvError::Closed) => {
+                    *pending = None;
+                    return;
+                }
+            }
+        };
+        // Keep showing the last list while the speaker isn't answering
+        let titles: Vec<String> = match result {
+            Ok(favourites) => favourites.into_iter().map(|f| f.title).collect(),
+            Err(e) => {
+                debug!("Failed to get favourites: {}", e);
+                return;
+            }
+        };
+        if *self.ivars().favourites.borrow() == titles {
+            return;
+        }
+        let Some(favourites_item) = self.ivars().favourites_item.get() else {
+            return;
+        };
+
+        let mtm = MainThreadMarker::from(self);
+        let favourites_menu = NSMenu::new(mtm);
+        for title in &titles {
+            let item = unsafe {
+                NSMenuItem::initWithTitle_action_keyEquivalent(
+                    NSMenuItem::alloc(mtm),
+                    &NSString::from_str(title),
+                    Some(objc2::sel!(favouriteClicked:)),
+                    &NSString::from_str(""),
+                )
+            };
+            unsafe { item.setTarget(Some(&self.retain())) };
+            favourites_menu.addItem(&item);
+        }
+        favourites_item.setSubmenu(Some(&favourites_menu));
+        unsafe { favourites_item.setHidden(titles.is_empty()) };
+        *self.ivars().favourites.borrow_mut() = titles;
+    }
+
+    // Pick up an edited config: rebuild the input items and adjust the refresh timer
+    fn apply_config(&self) {
+        let config = self.ivars().config.borrow_mut().borrow_and_update().clone();
+        info!("Applying updated config");
+
+        if let Some(menu) = self.ivars().menu.get() {
+            for item in self.ivars().input_items.borrow_mut().drain(..) {
+                unsafe { menu.removeItem(&item) };
+            }
+            self.add_input_items(menu, &config);
+        }
+
+        self.schedule_timer(config.ui_refresh());
+    }
+
+    // (Re)start the timer that processes poll updates, unless it already runs at `interval`
+    fn schedule_timer(&self, interval: Duration) {
+        let interval = interval.as_secs_f64() as NSTimeInterval;
+        let mut timer = self.ivars().timer.borrow_mut();
+        if let Some(old) = timer.as_ref() {
+            if unsafe { old.timeInterval() } == interval {
+                return;
+            }
+            unsafe { old.invalidate() };
+        }
+
+        *timer = Some(unsafe {
+            NSTimer::scheduledTimerWithTimeInterval_target_selector_userInfo_repeats(
+                interval,
+                &self.retain(),
+                sel!(processPollUpdates:),
+                None,
+                true,
+            )
+        });
+    }
 }
 
 pub fn run(
     tx: mpsc::UnboundedSender<SpeakerCommand>,
-    poll_rx: mpsc::UnboundedReceiver<SpeakerStatus>,
+    poll_rx: broadcast::Receiver<SpeakerStatus>,
+    config: watch::Receiver<Config>,
 ) {
     // This is required for GUI apps on macOS
     let mtm = MainThreadMarker::new().expect("Must be run on the main thread");
@@ -402,7 +592,7 @@ pub fn run(
     app.setActivationPolicy(NSApplicationActivationPolicy::Accessory);
 
     // Create and set our app delegate
-    let delegate = AppDelegate::new(mtm, tx, poll_rx);
+    let delegate = AppDelegate::new(mtm, tx, poll_rx, config);
 
     app.setDelegate(Some(ProtocolObject::from_ref(&*delegate)));
 
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use crate::{FadeCurve, FavouriteId, SpeakerCommand};
+
+use tokio::sync::{mpsc, oneshot};
+
+// The fade's last step can land a moment after its duration is up
+const FADE_GRACE: Duration = Duration::from_secs(2);
+
+/// Handle `qaf favourites`: list the speaker's favourites, numbered as `qaf play` takes them.
+pub async fn favourites(
+    json: bool,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::GetFavourites(reply))?;
+    let favourites = response.await??;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&favourites)?);
+    } else if favourites.is_empty() {
+        println!("No favourites; add some in the KEF app");
+    } else {
+        for favourite in &favourites {
+            println!("{:>3}  {}", favourite.index, favourite.title);
+        }
+    }
+    Ok(())
+}
+
+/// Handle `qaf play-url`.
+pub async fn url(
+    url: String,
+    title: Option<String>,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let title = title.unwrap_or_else(|| url.clone());
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::PlayUrl {
+        url,
+        title: title.clone(),
+        reply,
+    })?;
+    response.await??;
+    println!("Playing {title}");
+    Ok(())
+}
+
+/// Handle `qaf play`.
+pub async fn favourite(
+    favourite: FavouriteId,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::PlayFavourite { favourite, reply })?;
+    let title = response.await??;
+    println!("Playing {title}");
+    Ok(())
+}
+
+/// Handle `qaf fade`. The fade runs in the speaker controller, which stops with qaf, so this waits
+/// it out.
+pub async fn fade(
+    target: i32,
+    duration: Duration,
+    curve: FadeCurve,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    speaker_tx.send(SpeakerCommand::FadeVolume {
+        target,
+        duration,
+        curve,
+    })?;
+    println!("Fading to {target} over {}s", duration.as_secs());
+    tokio::time::sleep(duration + FADE_GRACE).await;
+
+    let (reply, response) = oneshot::channel();
+    speaker_tx.send(SpeakerCommand::GetStatus(reply))?;
+    if let Some(volume) = response.await?.volume {
+        println!("Volume is {volume}");
+    }
+    Ok(())
+}
@@ -0,0 +1,450 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::{InputSource, SpeakerCommand, alarm, cli::ScheduleCommand, store};
+
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone, Weekday};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+// Where schedules are saved
+const SCHEDULES: &str = "schedules.json";
+// How often the scheduler looks for rules that are due
+const TICK: Duration = Duration::from_secs(20);
+// A run missed while the Mac was asleep still happens on wake if it's no older than this;
+// anything older is skipped (powering off at 7:00 because 19:00 was missed helps nobody)
+const MISSED_RUN_GRACE: chrono::Duration = chrono::Duration::minutes(15);
+
+const ALL_DAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// Something a schedule does to the speaker. A schedule with several actions works as a scene,
+/// e.g. power on, switch to WiFi and set the volume.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    PowerOn,
+    PowerOff,
+    Input(InputSource),
+    Volume(i32),
+}
+
+impl Action {
+    fn command(self) -> SpeakerCommand {
+        match self {
+            Action::PowerOn => SpeakerCommand::PowerOn,
+            Action::PowerOff => SpeakerCommand::PowerOff,
+            Action::Input(input) => SpeakerCommand::SetInput(input),
+            Action::Volume(volume) => SpeakerCommand::SetVolume(volume),
+        }
+    }
+}
+
+// Actions are written `on`, `off`, `input=wifi` or `volume=30` on the command line
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "on" => Ok(Action::PowerOn),
+            None if s == "off" => Ok(Action::PowerOff),
+            Some(("input", input)) => InputSource::from_kef_source(input)
+                .map(Action::Input)
+                .ok_or_else(|| format!("unknown input {input:?}")),
+            Some(("volume", volume)) => match volume.parse() {
+                Ok(volume @ 0..=100) => Ok(Action::Volume(volume)),
+                _ => Err(format!("volume must be 0-100, not {volume:?}")),
+            },
+            _ => Err(format!(
+                "unknown action {s:?}; use on, off, input=<input> or volume=<0-100>"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::PowerOn => write!(f, "on"),
+            Action::PowerOff => write!(f, "off"),
+            Action::Input(input) => write!(f, "input={}", input.to_kef_source()),
+            Action::Volume(volume) => write!(f, "volume={volume}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: u32,
+    /// Local time of day to run at.
+    pub time: NaiveTime,
+    pub days: Vec<Weekday>,
+    pub actions: Vec<Action>,
+}
+
+// The most recent time something set for `time` on `days` should have run in `(after, until]`.
+// Generic over the time zone so tests can pin down daylight saving changes.
+fn last_due<Tz: TimeZone>(
+    time: NaiveTime,
+    days: &[Weekday],
+    after: DateTime<Tz>,
+    until: DateTime<Tz>,
+) -> Option<DateTime<Tz>> {
+    // Looking back a week covers every weekday, however long the gap
+    let first = after
+        .date_naive()
+        .max(until.date_naive() - chrono::Days::new(7));
+    let mut date = until.date_naive();
+    while date >= first {
+        if days.contains(&date.weekday())
+            && let Some(at) = until
+                .timezone()
+                .from_local_datetime(&date.and_time(time))
+                .earliest()
+            && at > after
+            && at <= until
+        {
+            return Some(at);
+        }
+        date = date.pred_opt()?;
+    }
+    None
+}
+
+// Whether `what`, set for `time` on `days`, should run now, given the last check was at `after`
+fn is_due<Tz: TimeZone>(
+    what: &str,
+    time: NaiveTime,
+    days: &[Weekday],
+    after: DateTime<Tz>,
+    now: DateTime<Tz>,
+) -> bool
+where
+    Tz::Offset: fmt::Display,
+{
+    let Some(due) = last_due(time, days, after, now.clone()) else {
+        return false;
+    };
+    if now - due.clone() > MISSED_RUN_GRACE {
+        warn!("Skipping {} missed at {}", what, due.format("%a %H:%M"));
+        return false;
+    }
+    info!("Running {} due at {}", what, due.format("%H:%M"));
+    true
+}
+
+/// Parse a list of days: `daily`, `weekdays`, `weekends`, day names (`mon,wed`) and ranges
+/// (`mon-fri`), separated by commas.
+pub fn parse_days(s: &str) -> Result<Vec<Weekday>, String> {
+    let mut days = Vec::new();
+    for part in s.split(',').map(str::trim) {
+        let parse = |day: &str| Weekday::from_str(day).map_err(|_| format!("unknown day {day:?}"));
+        let range = match part {
+            "daily" => ALL_DAYS.to_vec(),
+            "weekdays" => ALL_DAYS[..5].to_vec(),
+            "weekends" => ALL_DAYS[5..].to_vec(),
+            _ => match part.split_once('-') {
+                Some((from, to)) => {
+                    let (from, to) = (parse(from)?, parse(to)?);
+                    let mut range = vec![from];
+                    let mut day = from;
+                    while day != to {
+                        day = day.succ();
+                        range.push(day);
+                    }
+                    range
+                }
+                None => vec![parse(part)?],
+            },
+        };
+        for day in range {
+            if !days.contains(&day) {
+                days.push(day);
+            }
+        }
+    }
+    days.sort_by_key(Weekday::num_days_from_monday);
+    Ok(days)
+}
+
+pub fn format_days(days: &[Weekday]) -> String {
+    match days {
+        d if d == ALL_DAYS => "daily".to_string(),
+        d if d == &ALL_DAYS[..5] => "weekdays".to_string(),
+        d if d == &ALL_DAYS[5..] => "weekends".to_string(),
+        _ => days
+            .iter()
+            .map(|day| day.to_string().to_lowercase())
+            .collect::<Vec<_>>()
+            .join(","),
+    }
+}
+
+fn load() -> Vec<Schedule> {
+    store::load(SCHEDULES).unwrap_or_default()
+}
+
+/// Run the schedules and alarms against the speaker controller. Both are re-read on every tick,
+/// so edits made with `qaf schedule` and `qaf alarm` are picked up without a restart.
+pub async fn run(speaker_tx: mpsc::UnboundedSender<SpeakerCommand>) {
+    let mut interval = tokio::time::interval(TICK);
+    // Wall clock time of the last check. The tokio clock doesn't advance while the Mac sleeps,
+    // so gaps are measured on the wall clock to notice runs that were slept through.
+    let mut last_check = Local::now();
+
+    loop {
+        interval.tick().await;
+        let now = Local::now();
+        if (now - last_check).to_std().unwrap_or_default() > TICK * 2 {
+            debug!(
+                "Scheduler woke up after {}s",
+                (now - last_check).num_seconds()
+            );
+        }
+
+        let mut commands = Vec::new();
+        for schedule in load() {
+            let what = format!("schedule {}", schedule.id);
+            if is_due(&what, schedule.time, &schedule.days, last_check, now) {
+                commands.extend(schedule.actions.iter().map(|action| action.command()));
+            }
+        }
+        for alarm in alarm::load() {
+            let what = format!("alarm {}", alarm.id);
+            if is_due(&what, alarm.time, &alarm.days, last_check, now) {
+                commands.push(SpeakerCommand::Wake(alarm.wake_up()));
+            }
+        }
+        for command in commands {
+            if speaker_tx.send(command).is_err() {
+                error!("Speaker controller is gone, stopping scheduler");
+                return;
+            }
+        }
+        last_check = now;
+    }
+}
+
+/// Handle `qaf schedule …`.
+pub fn cli(command: ScheduleCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let mut schedules = load();
+    match command {
+        ScheduleCommand::List => {
+            if schedules.is_empty() {
+                println!("No schedules");
+            }
+            for schedule in &schedules {
+                let actions: Vec<_> = schedule.actions.iter().map(Action::to_string).collect();
+                println!(
+                    "{:>3}  {}  {:<20}  {}",
+                    schedule.id,
+                    schedule.time.format("%H:%M"),
+                    format_days(&schedule.days),
+                    actions.join(" ")
+                );
+            }
+        }
+        ScheduleCommand::Add {
+            time,
+            days,
+            actions,
+        } => {
+            let schedule = Schedule {
+                id: schedules.iter().map(|s| s.id).max().unwrap_or(0) + 1,
+                time,
+                days: parse_days(&days)?,
+                actions,
+            };
+            let id = schedule.id;
+            schedules.push(schedule);
+            store::save(SCHEDULES, &schedules)?;
+            println!("Added schedule {id}");
+        }
+        ScheduleCommand::Remove { id } => {
+            let before = schedules.len();
+            schedules.retain(|s| s.id != id);
+            if schedules.len() == before {
+                return Err(format!("no schedule {id}").into());
+            }
+            store::save(SCHEDULES, &schedules)?;
+            println!("Removed schedule {id}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::{FixedOffset, LocalResult, NaiveDate, NaiveDateTime};
+
+    // UK time in 2026: clocks go forward at 01:00 on 29 March and back at 02:00 on 25 October
+    #[derive(Debug, Clone, Copy)]
+    struct London;
+
+    impl London {
+        fn gmt() -> FixedOffset {
+            FixedOffset::east_opt(0).unwrap()
+        }
+
+        fn bst() -> FixedOffset {
+            FixedOffset::east_opt(3600).unwrap()
+        }
+    }
+
+    impl TimeZone for London {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            London
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let fits = |offset: FixedOffset| {
+                let utc = *local - chrono::Duration::seconds(offset.local_minus_utc().into());
+                self.offset_from_utc_datetime(&utc) == offset
+            };
+            match (fits(London::bst()), fits(London::gmt())) {
+                (true, true) => LocalResult::Ambiguous(London::bst(), London::gmt()),
+                (true, false) => LocalResult::Single(London::bst()),
+                (false, true) => LocalResult::Single(London::gmt()),
+                (false, false) => LocalResult::None,
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let change = |month, day| {
+                NaiveDate::from_ymd_opt(2026, month, day)
+                    .unwrap()
+                    .and_hms_opt(1, 0, 0)
+                    .unwrap()
+            };
+            if (change(3, 29)..change(10, 25)).contains(utc) {
+                London::bst()
+            } else {
+                London::gmt()
+            }
+        }
+    }
+
+    fn local(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn at(s: &str) -> DateTime<London> {
+        London.from_local_datetime(&local(s)).earliest().unwrap()
+    }
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn parses_days() {
+        assert_eq!(parse_days("daily").unwrap(), ALL_DAYS);
+        assert_eq!(
+            parse_days("weekends").unwrap(),
+            [Weekday::Sat, Weekday::Sun]
+        );
+        assert_eq!(
+            parse_days("wed, mon,wed").unwrap(),
+            [Weekday::Mon, Weekday::Wed]
+        );
+        assert_eq!(parse_days("mon-fri").unwrap(), &ALL_DAYS[..5]);
+        assert!(parse_days("mon-someday").is_err());
+        assert!(parse_days("").is_err());
+    }
+
+    #[test]
+    fn day_ranges_wrap_around_the_week() {
+        assert_eq!(
+            parse_days("fri-mon").unwrap(),
+            [Weekday::Mon, Weekday::Fri, Weekday::Sat, Weekday::Sun]
+        );
+        assert_eq!(parse_days("sun-sun").unwrap(), [Weekday::Sun]);
+    }
+
+    #[test]
+    fn finds_the_run_in_the_window() {
+        // 2026-10-12 is a Monday
+        let days = parse_days("weekdays").unwrap();
+        let seven = time("07:00");
+        assert_eq!(
+            last_due(seven, &days, at("2026-10-12 06:59"), at("2026-10-12 07:00")),
+            Some(at("2026-10-12 07:00"))
+        );
+        assert_eq!(
+            last_due(seven, &days, at("2026-10-12 07:00"), at("2026-10-12 07:20")),
+            None
+        );
+        // Not on weekends
+        assert_eq!(
+            last_due(seven, &days, at("2026-10-17 06:00"), at("2026-10-18 08:00")),
+            None
+        );
+        // Asleep from Friday evening to Monday morning: Monday's run is the one that's due
+        assert_eq!(
+            last_due(seven, &days, at("2026-10-09 18:00"), at("2026-10-12 07:10")),
+            Some(at("2026-10-12 07:00"))
+        );
+    }
+
+    #[test]
+    fn runs_missed_runs_only_if_recent() {
+        let days = ALL_DAYS;
+        let seven = time("07:00");
+        let slept = at("2026-10-12 06:00");
+        assert!(is_due("test", seven, &days, slept, at("2026-10-12 07:15")));
+        assert!(!is_due("test", seven, &days, slept, at("2026-10-12 07:16")));
+        assert!(!is_due("test", seven, &days, slept, at("2026-10-12 06:59")));
+    }
+
+    #[test]
+    fn runs_once_when_the_clocks_go_back() {
+        // 01:30 happens twice on 25 October; only the first one runs
+        let half_one = time("01:30");
+        let first = at("2026-10-25 01:30");
+        let second = London
+            .from_local_datetime(&local("2026-10-25 01:30"))
+            .latest()
+            .unwrap();
+        assert_eq!(second - first, chrono::Duration::hours(1));
+
+        assert_eq!(
+            last_due(half_one, &ALL_DAYS, at("2026-10-25 01:00"), first),
+            Some(first)
+        );
+        assert_eq!(last_due(half_one, &ALL_DAYS, first, second), None);
+    }
+
+    #[test]
+    fn skips_times_the_clocks_jump_over() {
+        // There's no 01:30 on 29 March
+        let half_one = time("01:30");
+        let before = at("2026-03-29 00:59");
+        assert_eq!(
+            last_due(half_one, &ALL_DAYS, before, at("2026-03-29 03:00")),
+            None
+        );
+        assert_eq!(
+            last_due(half_one, &ALL_DAYS, before, at("2026-03-30 01:30")),
+            Some(at("2026-03-30 01:30"))
+        );
+    }
+}
@@ -1,27 +1,410 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::{InputSource, SpeakerCommand, SpeakerInfo, SpeakerStatus};
+use crate::{
+    Connectivity, DeviceInfo, DeviceSetting, DeviceSettings, FadeCurve, Favourite, FavouriteId,
+    FirmwareUpdate, InputSource, NowPlaying, Setting, SpeakerCommand, SpeakerInfo, SpeakerStatus,
+    StandbyMode, config::Config, store,
+};
 
+use chrono::Local;
 use mdns_sd::{ServiceDaemon, ServiceEvent};
 use serde_json::json;
-use tokio::{sync::mpsc, time::sleep};
+use tokio::{
+    sync::{mpsc, watch},
+    task::{JoinError, JoinHandle, JoinSet},
+    time::{Instant, MissedTickBehavior, interval, sleep, sleep_until},
+};
 use tracing::{debug, error, info, trace, warn};
 
+/// Speaker API calls. Cheap to clone, so reads and fades can run alongside the controller.
+#[derive(Clone)]
+struct Api {
+    client: reqwest::Client,
+    base_url: String,
+    retry: Retry,
+    // Shared by every clone, so all requests count towards it
+    health: Arc<Mutex<Connectivity>>,
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// How failed requests to the speaker are retried.
+#[derive(Debug, Clone, Copy)]
+struct Retry {
+    retries: u32,
+    // Wait before the first retry, doubled for each one after it
+    backoff: Duration,
+}
+
+/// The step of switching input that failed.
+#[derive(Debug)]
+enum SwitchError {
+    PowerOn(Error),
+    NotReady(Duration),
+    SetInput(Error),
+    Verify(Error),
+    Unchanged(Option<InputSource>),
+}
+
+impl fmt::Display for SwitchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwitchError::PowerOn(e) => write!(f, "powering on failed: {e}"),
+            SwitchError::NotReady(timeout) => {
+                write!(f, "speaker still not on after {}s", timeout.as_secs_f64())
+            }
+            SwitchError::SetInput(e) => write!(f, "setting the input failed: {e}"),
+            SwitchError::Verify(e) => write!(f, "reading back the input failed: {e}"),
+            SwitchError::Unchanged(Some(source)) => {
+                write!(f, "speaker ignored the switch and is still on {source:?}")
+            }
+            SwitchError::Unchanged(None) => write!(f, "speaker ignored the switch"),
+        }
+    }
+}
+
+impl std::error::Error for SwitchError {}
+
+const PHYSICAL_SOURCE: &str = "settings:/kef/play/physicalSource";
+const SPEAKER_STATUS: &str = "settings:/kef/host/speakerStatus";
+pub const MAXIMUM_VOLUME: &str = "settings:/kef/host/maximumVolume";
+const VOLUME: &str = "player:volume";
+pub const DEVICE_NAME: &str = "settings:/deviceName";
+const EQ_PROFILE: &str = "kef:eqProfile/v2";
+const STANDBY_MODE: &str = "settings:/kef/host/standbyMode";
+const STARTUP_TONE: &str = "settings:/kef/host/startupTone";
+const STANDBY_LED: &str = "settings:/kef/host/disableFrontStandbyLED";
+const FRONT_LED: &str = "settings:/kef/host/disableFrontLED";
+const TOP_PANEL_LOCK: &str = "settings:/kef/host/topPanelLock";
+const AUTO_WAKE: &str = "settings:/kef/host/autoWake";
+const FIRMWARE: &str = "settings:/releasetext";
+const SERIAL_NUMBER: &str = "settings:/kef/host/serialNumber";
+const MAC_ADDRESS: &str = "settings:/system/primaryMacAddress";
+const NETWORK_INFO: &str = "network:info";
+const FAVOURITES: &str = "ui:/favourites";
+const PLAYER_CONTROL: &str = "player:player/control";
+const UPDATE_STATUS: &str = "firmwareupdate:updateStatus";
+const UPDATE_CHECK: &str = "firmwareupdate:checkForUpdate";
+const UPDATE_START: &str = "firmwareupdate:startUpdate";
+const CABLE_MODE: &str = "settings:/kef/host/cableMode";
+const MASTER_CHANNEL: &str = "settings:/kef/host/masterChannelMode";
+
+/// Settings a user can change on the speaker, as saved by `qaf backup`.
+pub const USER_SETTINGS: &[&str] = &[
+    DEVICE_NAME,
+    EQ_PROFILE,
+    STANDBY_MODE,
+    STARTUP_TONE,
+    STANDBY_LED,
+    FRONT_LED,
+    TOP_PANEL_LOCK,
+    AUTO_WAKE,
+    MAXIMUM_VOLUME,
+    CABLE_MODE,
+    MASTER_CHANNEL,
+];
+
+// How long the speaker gets to hear back from KEF about updates, and how often to ask it
+const UPDATE_CHECK_WAIT: Duration = Duration::from_secs(30);
+const UPDATE_CHECK_POLL: Duration = Duration::from_secs(1);
+
+// How long a renamed speaker gets to announce its new name
+const RENAME_WAIT: Duration = Duration::from_secs(30);
+
+// Rows asked for at a time when browsing settings
+const BROWSE_PAGE: usize = 100;
+// Browsing gives up beyond this many settings, in case the tree loops back on itself
+const BROWSE_LIMIT: usize = 10_000;
+
+// Where the last speaker we talked to is remembered
+const SPEAKER_CACHE: &str = "speaker.json";
+// Where the last volume used on each input is remembered
+const VOLUMES: &str = "volumes.json";
+// How long the remembered speaker gets to answer before we go looking for it with mDNS
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+// Longest wait between retries, however many there have been
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+// The sleep timer fades the volume out over this much of its final stretch
+const SLEEP_FADE: Duration = Duration::from_secs(60);
+// Time between volume steps while fading
+const FADE_STEP: Duration = Duration::from_secs(1);
+// How often the speaker is asked whether it's on yet after powering on
+const READY_POLL: Duration = Duration::from_millis(250);
+// How often the volume is checked against the volume limit, to catch the remote and the KEF app
+const LIMIT_CHECK: Duration = Duration::from_secs(5);
+
+struct SleepTimer {
+    deadline: Instant,
+    // When to start fading out, if the timer fades and hasn't started to yet
+    fade_start: Option<Instant>,
+    // Volume when the fade started, restored after powering off
+    fade_from: Option<i32>,
+}
+
+impl SleepTimer {
+    // When the controller next has something to do for this timer
+    fn next_wake(&self) -> Instant {
+        self.fade_start.unwrap_or(self.deadline)
+    }
+}
+
 pub struct SpeakerController {
-    rx: mpsc::UnboundedReceiver<SpeakerCommand>,
     info: SpeakerInfo,
-    client: reqwest::Client,
+    // Where frontends see the speaker's info, updated when it's renamed
+    info_tx: watch::Sender<Option<SpeakerInfo>>,
+    api: Api,
+    config: watch::Receiver<Config>,
+    sleep_timer: Option<SleepTimer>,
+    // Volume fade running in the background, if any
+    fade: Option<JoinHandle<()>>,
+    // Input last seen on the speaker, for per-input volume limits
+    source: Option<InputSource>,
+    // Maximum volume last written to the speaker's own setting
+    synced_max: Option<i32>,
+    // Set once the speaker has refused a maximum volume, so we stop trying
+    max_volume_unsupported: bool,
+    // Last volume used on each input
+    volumes: BTreeMap<InputSource, i32>,
+    // Set while a firmware update runs, when writes are refused
+    updating: bool,
+}
+
+/// Commands waiting for the controller: writes not started yet, and status reads under way.
+#[derive(Default)]
+struct Queue {
+    writes: VecDeque<SpeakerCommand>,
+    // Each read hands back the generation it started in, and the status it found if any
+    reads: JoinSet<(u64, Option<SpeakerStatus>)>,
+    // Writes finished so far
+    generation: u64,
+}
+
+impl Queue {
+    fn spawn_read(&mut self, read: impl Future<Output = Option<SpeakerStatus>> + Send + 'static) {
+        let generation = self.generation;
+        self.reads.spawn(async move { (generation, read.await) });
+    }
+
+    // A finished read's status, unless a write has finished since the read started: the status
+    // may be from before the write landed, so the controller shouldn't learn from it
+    fn learn(
+        &self,
+        result: Result<(u64, Option<SpeakerStatus>), JoinError>,
+    ) -> Option<SpeakerStatus> {
+        match result {
+            Ok((generation, status)) if generation == self.generation => status,
+            _ => None,
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        command: SpeakerCommand,
+        api: &Api,
+        info: &watch::Sender<Option<SpeakerInfo>>,
+        sleep_deadline: Option<Instant>,
+    ) {
+        let sleep_remaining = move || {
+            sleep_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+        };
+        match command {
+            SpeakerCommand::GetStatus(tx) => {
+                debug!("Getting speaker status");
+                let api = api.clone();
+                self.spawn_read(async move {
+                    match api.status().await {
+                        Ok(mut status) => {
+                            status.sleep_timer = sleep_remaining();
+                            status.update = api.get_update().await.unwrap_or_else(|e| {
+                                debug!("No firmware update status: {}", e);
+                                None
+                            });
+                            status.connectivity = api.connectivity();
+                            let _ = tx.send(status.clone());
+                            Some(status)
+                        }
+                        Err(e) => {
+                            error!("Failed to get status: {}", e);
+                            let _ = tx.send(SpeakerStatus {
+                                power: "unknown".to_string(),
+                                source: None,
+                                volume: None,
+                                now_playing: None,
+                                sleep_timer: sleep_remaining(),
+                                connectivity: api.connectivity(),
+                                update: None,
+                            });
+                            None
+                        }
+                    }
+                });
+            }
+            SpeakerCommand::PollUpdate(status) => {
+                // This is handled by the UI, just log it
+                trace!("Poll update received: {:?}", status);
+            }
+            SpeakerCommand::Browse { path, reply } => {
+                let api = api.clone();
+                self.spawn_read(async move {
+                    let result = api.browse(&path).await.map_err(|e| e.to_string());
+                    let _ = reply.send(result);
+                    None
+                });
+            }
+            SpeakerCommand::GetFavourites(reply) => {
+                let api = api.clone();
+                self.spawn_read(async move {
+                    let _ = reply.send(api.favourites().await.map_err(|e| e.to_string()));
+                    None
+                });
+            }
+            SpeakerCommand::GetDeviceInfo(reply) => {
+                let api = api.clone();
+                // The latest info, with the name from after any rename
+                let info = info.borrow().clone();
+                self.spawn_read(async move {
+                    let result = match info {
+                        Some(info) => api.get_device_info(info).await.map_err(|e| e.to_string()),
+                        None => Err("speaker not found yet".to_string()),
+                    };
+                    let _ = reply.send(result);
+                    None
+                });
+            }
+            SpeakerCommand::GetDeviceSettings(reply) => {
+                let api = api.clone();
+                self.spawn_read(async move {
+                    let result = api.get_device_settings().await;
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                    None
+                });
+            }
+            SpeakerCommand::Raw {
+                path,
+                value: None,
+                reply,
+            } => {
+                let api = api.clone();
+                self.spawn_read(async move {
+                    let _ = reply.send(api.raw(&path, None).await);
+                    None
+                });
+            }
+            command => {
+                // Five quick volume clicks only need the last one sent
+                if let Some(last) = self.writes.back()
+                    && supersedes(&command, last)
+                {
+                    debug!("Dropping queued {:?}, superseded by {:?}", last, command);
+                    self.writes.pop_back();
+                }
+                self.writes.push_back(command);
+            }
+        }
+    }
+}
+
+// Whether carrying out `new` straight after `old` makes `old` pointless
+fn supersedes(new: &SpeakerCommand, old: &SpeakerCommand) -> bool {
+    use SpeakerCommand::*;
+    matches!(
+        (new, old),
+        (
+            SetVolume(_) | FadeVolume { .. },
+            SetVolume(_) | FadeVolume { .. }
+        ) | (SetInput(_), SetInput(_))
+            | (
+                SleepTimer(_) | CancelSleepTimer,
+                SleepTimer(_) | CancelSleepTimer
+            )
+            | (PowerOn, PowerOn)
+            | (PowerOff, PowerOn | PowerOff)
+    )
 }
 
 impl SpeakerController {
-    pub fn new(info: SpeakerInfo, rx: mpsc::UnboundedReceiver<SpeakerCommand>) -> Self {
+    pub fn new(
+        info: SpeakerInfo,
+        info_tx: watch::Sender<Option<SpeakerInfo>>,
+        config: watch::Receiver<Config>,
+    ) -> Self {
+        let api = Api::new(&info.base_url, &config.borrow());
         Self {
-            rx,
             info,
-            client: reqwest::Client::new(),
+            info_tx,
+            api,
+            config,
+            sleep_timer: None,
+            fade: None,
+            source: None,
+            synced_max: None,
+            max_volume_unsupported: false,
+            volumes: store::load(VOLUMES).unwrap_or_default(),
+            updating: false,
+        }
+    }
+
+    /// Find the speaker to talk to. The one used last time is tried first, and only if it doesn't
+    /// answer (or isn't the `preferred` one) is the network searched.
+    pub async fn connect(preferred: Option<String>) -> Option<SpeakerInfo> {
+        let cached = store::load::<SpeakerInfo>(SPEAKER_CACHE);
+        if let Some(cached) = cached.clone()
+            && preferred
+                .as_ref()
+                .is_none_or(|p| *p == cached.name || *p == cached.address)
+        {
+            if Self::probe(&cached).await {
+                info!("Using {} at {}", cached.name, cached.base_url);
+                return Some(cached);
+            }
+            info!("{} is not answering, searching the network", cached.name);
+        } else if let (Some(cached), Some(preferred)) = (&cached, &preferred) {
+            // Also what's left behind by renaming the speaker
+            warn!(
+                "The config picks speaker {:?}, not {:?} at {} used last time; searching the network",
+                preferred, cached.name, cached.address
+            );
+        }
+
+        let info = match tokio::task::spawn_blocking(move || {
+            Self::discover_speaker(preferred.as_deref(), None)
+        })
+        .await
+        {
+            Ok(info) => info?,
+            Err(e) => {
+                error!("Speaker discovery failed: {}", e);
+                return None;
+            }
+        };
+        if let Err(e) = store::save(SPEAKER_CACHE, &info) {
+            warn!("Failed to remember speaker: {}", e);
         }
+        Some(info)
     }
-    pub fn discover_speaker() -> Option<SpeakerInfo> {
+
+    // Quick health check: does the speaker answer a status request in time?
+    async fn probe(info: &SpeakerInfo) -> bool {
+        match Api::probe(&info.base_url).get_power().await {
+            Ok(_) => true,
+            Err(e) => {
+                debug!("Probe of {} failed: {}", info.base_url, e);
+                false
+            }
+        }
+    }
+
+    /// Find a KEF speaker on the network. With `preferred` set, speakers whose name or address
+    /// don't match it are skipped. Searches until one is found, or for at most `wait`.
+    pub fn discover_speaker(
+        preferred: Option<&str>,
+        wait: Option<Duration>,
+    ) -> Option<SpeakerInfo> {
         debug!("Starting mDNS discovery for KEF speakers…");
         let service_type = "_kef-info._tcp.local.";
         let mdns = match ServiceDaemon::new() {
@@ -41,7 +424,11 @@ impl SpeakerController {
         };
         debug!("Searching for KEF speakers on the network...");
         let mut speaker_info = None;
-        while let Ok(event) = receiver.recv() {
+        let deadline = wait.map(|wait| std::time::Instant::now() + wait);
+        while let Some(event) = match deadline {
+            Some(deadline) => receiver.recv_deadline(deadline).ok(),
+            None => receiver.recv().ok(),
+        } {
             if let ServiceEvent::ServiceResolved(info) = event {
                 trace!("Found KEF speaker: {}", info.get_fullname());
 
@@ -61,6 +448,13 @@ impl SpeakerController {
                         "KEF Speaker discovered - Name: {}, Model: {}, Address: {}:{}",
                         name, model, addr, port
                     );
+                    if let Some(preferred) = preferred
+                        && preferred != name
+                        && preferred != addr.to_string()
+                    {
+                        debug!("Skipping {}, looking for {}", name, preferred);
+                        continue;
+                    }
                     let address = addr.to_string();
                     speaker_info = Some(SpeakerInfo {
                         address,
@@ -94,208 +488,1533 @@ impl SpeakerController {
         speaker_info
     }
 
-    pub async fn run(mut self) {
+    /// Handle commands until every sender is gone. Commands that change the speaker are carried
+    /// out one at a time, in order; status queries don't wait for them and run alongside.
+    pub async fn run(mut self, mut rx: mpsc::UnboundedReceiver<SpeakerCommand>) {
         debug!("Speaker controller started, waiting for speaker discovery...");
+        let mut limit_check = interval(LIMIT_CHECK);
+        limit_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut queue = Queue::default();
+        let mut closed = false;
 
-        while let Some(command) = self.rx.recv().await {
-            match command {
-                SpeakerCommand::SetInput(input) => {
-                    debug!("Setting input to: {:?}", input);
+        loop {
+            // Clear out reads that finished while the last write was running; they started before
+            // it finished, so there is nothing to learn from them
+            while let Some(result) = queue.reads.try_join_next() {
+                if let Some(status) = queue.learn(result) {
+                    self.observe(&status);
+                }
+            }
 
-                    // First check if we need to power on
-                    if let Ok(status) = self.get_speaker_status().await
-                        && status.power == "standby"
-                    {
-                        debug!("Speaker is in standby, powering on first");
-                        if let Err(e) = self.power_on().await {
-                            error!("Failed to power on: {}", e);
-                            continue;
+            let Some(command) = queue.writes.pop_front() else {
+                if closed {
+                    break;
+                }
+                let sleep_wake = self.sleep_timer.as_ref().map(SleepTimer::next_wake);
+                let limiting = {
+                    let limit = &self.config.borrow().volume_limit;
+                    (limit.is_active() || limit.sync_speaker || self.synced_max.is_some())
+                        && !self.updating
+                };
+                tokio::select! {
+                    command = rx.recv() => match command {
+                        Some(command) => queue.dispatch(command, &self.api, &self.info_tx, self.sleep_deadline()),
+                        None => closed = true,
+                    },
+                    Some(result) = queue.reads.join_next() => {
+                        if let Some(status) = queue.learn(result) {
+                            self.observe(&status);
                         }
-                        // Wait a bit for the speaker to power on
-                        sleep(Duration::from_millis(500)).await;
                     }
-
-                    if let Err(e) = self.set_input(input).await {
-                        error!("Failed to set input: {}", e);
+                    _ = sleep_until(sleep_wake.unwrap_or_else(Instant::now)), if sleep_wake.is_some() => {
+                        self.sleep_timer_tick().await;
+                    }
+                    Ok(()) = self.config.changed() => {
+                        // Timeouts may have changed
+                        self.api = Api {
+                            health: self.api.health.clone(),
+                            ..Api::new(&self.info.base_url, &self.config.borrow_and_update())
+                        };
+                    }
+                    _ = limit_check.tick(), if limiting => {
+                        self.enforce_volume_limit().await;
                     }
                 }
-                SpeakerCommand::GetStatus(tx) => {
-                    debug!("Getting speaker status");
-                    match self.get_speaker_status().await {
-                        Ok(status) => {
-                            let _ = tx.send(status);
-                        }
-                        Err(e) => {
-                            error!("Failed to get status: {}", e);
-                            let _ = tx.send(SpeakerStatus {
-                                power: "unknown".to_string(),
-                                source: None,
-                            });
-                        }
+                continue;
+            };
+
+            // Keep taking commands while the write runs. Reads still under way when it finishes,
+            // whenever they started, may be from before it landed and won't be learned from.
+            let api = self.api.clone();
+            let info_tx = self.info_tx.clone();
+            let sleep_deadline = self.sleep_deadline();
+            let write = self.execute(command);
+            tokio::pin!(write);
+            loop {
+                tokio::select! {
+                    () = &mut write => break,
+                    command = rx.recv(), if !closed => match command {
+                        Some(command) => queue.dispatch(command, &api, &info_tx, sleep_deadline),
+                        None => closed = true,
+                    },
+                }
+            }
+            queue.generation += 1;
+        }
+
+        info!("Speaker controller shutting down");
+    }
+
+    async fn execute(&mut self, command: SpeakerCommand) {
+        if self.updating {
+            refuse(command, "a firmware update is running");
+            return;
+        }
+        match command {
+            SpeakerCommand::SetInput(input) => {
+                debug!("Setting input to: {:?}", input);
+                if let Err(e) = self.select_input(input).await {
+                    error!("Failed to switch to {:?}: {}", input, e);
+                }
+            }
+            SpeakerCommand::SetVolume(volume) => {
+                debug!("Setting volume to: {}", volume);
+                // Someone wants a particular volume, so stop fading towards another one
+                self.cancel_fade();
+                match self.set_volume(volume).await {
+                    Ok(volume) => self.remember_volume(self.source, Some(volume)),
+                    Err(e) => error!("Failed to set volume: {}", e),
+                }
+            }
+            SpeakerCommand::PowerOn => {
+                info!("Powering on speakers");
+                if let Err(e) = self.api.power_on().await {
+                    error!("Failed to power on: {}", e);
+                    return;
+                }
+
+                let default_input = self.config.borrow().default_input;
+                if let Some(input) = default_input {
+                    debug!("Switching to default input {:?}", input);
+                    let result = match self.wait_until_ready().await {
+                        Ok(()) => self.switch_input(input).await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(()) => self.restore_volume(input).await,
+                        Err(e) => error!("Failed to switch to {:?}: {}", input, e),
                     }
                 }
-                SpeakerCommand::PowerOn => {
-                    info!("Powering on speakers");
-                    if let Err(e) = self.power_on().await {
-                        error!("Failed to power on: {}", e);
+                self.enforce_volume_limit().await;
+            }
+            SpeakerCommand::PowerOff => {
+                info!("Powering off speakers");
+                if let Err(e) = self.api.power_off().await {
+                    error!("Failed to power off: {}", e);
+                }
+                // Nothing left for a sleep timer or fade to do
+                self.cancel_sleep_timer().await;
+                self.cancel_fade();
+            }
+            SpeakerCommand::SleepTimer(duration) => {
+                info!("Sleep timer set to {:?}", duration);
+                self.cancel_sleep_timer().await;
+                let deadline = Instant::now() + duration;
+                let fade = self.config.borrow().sleep_fade;
+                self.sleep_timer = Some(SleepTimer {
+                    deadline,
+                    fade_start: fade.then(|| deadline - SLEEP_FADE.min(duration)),
+                    fade_from: None,
+                });
+            }
+            SpeakerCommand::CancelSleepTimer => {
+                info!("Sleep timer cancelled");
+                self.cancel_sleep_timer().await;
+            }
+            SpeakerCommand::FadeVolume {
+                target,
+                duration,
+                curve,
+            } => {
+                debug!("Fading volume to {} over {:?}", target, duration);
+                self.start_fade(target, duration, curve);
+            }
+            SpeakerCommand::Wake(wake) => {
+                info!("Waking up: {:?}", wake);
+                self.cancel_fade();
+
+                let powered = matches!(
+                    self.api.status().await,
+                    Ok(status) if status.power == "powerOn"
+                );
+                if !powered && let Err(e) = self.wake_speaker().await {
+                    error!("Failed to wake up: {}", e);
+                    return;
+                }
+                if let Some(input) = wake.input
+                    && let Err(e) = self.switch_input(input).await
+                {
+                    error!("Failed to switch to {:?}: {}", input, e);
+                }
+                if let Err(e) = self.set_volume(wake.from).await {
+                    error!("Failed to set volume: {}", e);
+                    return;
+                }
+                self.start_fade(wake.to, wake.over, FadeCurve::Linear);
+            }
+            SpeakerCommand::Raw { path, value, reply } => {
+                info!("Setting {} to {:?}", path, value);
+                let _ = reply.send(self.api.raw(&path, value).await);
+            }
+            SpeakerCommand::SetDeviceName { name, reply } => {
+                info!("Renaming speaker to {:?}", name);
+                if let Err(e) = self.api.set(DEVICE_NAME, name.clone()).await {
+                    let _ = reply.send(Err(e.to_string()));
+                    return;
+                }
+                if self.config.borrow().speaker.as_ref() == Some(&self.info.name) {
+                    warn!(
+                        "The config picks the speaker by its old name {:?}; change `speaker` to {:?}",
+                        self.info.name, name
+                    );
+                }
+                // Waiting for the announcement shouldn't hold up other commands
+                let info = self.info.clone();
+                self.info.name = name.clone();
+                let info_tx = self.info_tx.clone();
+                tokio::spawn(async move {
+                    let info = renamed(info, name).await;
+                    if let Err(e) = store::save(SPEAKER_CACHE, &info) {
+                        warn!("Failed to remember speaker: {}", e);
                     }
+                    info_tx.send_replace(Some(info));
+                    let _ = reply.send(Ok(()));
+                });
+            }
+            SpeakerCommand::PlayFavourite { favourite, reply } => {
+                info!("Playing favourite {}", favourite);
+                let result = self.play_favourite(&favourite).await;
+                if let Err(e) = &result {
+                    error!("Failed to play favourite {}: {}", favourite, e);
+                }
+                let _ = reply.send(result.map_err(|e| e.to_string()));
+            }
+            SpeakerCommand::PlayUrl { url, title, reply } => {
+                info!("Playing {} as {:?}", url, title);
+                let result = self.play(&stream_roles(&url, &title)).await;
+                if let Err(e) = &result {
+                    error!("Failed to play {}: {}", url, e);
+                }
+                let _ = reply.send(result.map_err(|e| e.to_string()));
+            }
+            // Queued rather than run alongside, as it has the speaker do something
+            SpeakerCommand::CheckFirmwareUpdate(reply) => {
+                info!("Checking for a firmware update");
+                if let Err(e) = self.api.check_update().await {
+                    let _ = reply.send(Err(e.to_string()));
+                    return;
+                }
+                // Waiting for the answer shouldn't hold up other commands
+                let api = self.api.clone();
+                tokio::spawn(async move {
+                    let result = api.checked_update().await;
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                });
+            }
+            SpeakerCommand::StartFirmwareUpdate(reply) => {
+                info!("Starting firmware update");
+                let result = self.api.start_update().await;
+                if result.is_ok() {
+                    // The speaker restarts at the end, so there's nothing left for these to do
+                    self.updating = true;
+                    self.cancel_fade();
+                    self.cancel_sleep_timer().await;
+                }
+                let _ = reply.send(result.map_err(|e| e.to_string()));
+            }
+            SpeakerCommand::SetDeviceSetting { setting, reply } => {
+                info!("Changing device setting: {:?}", setting);
+                let result = self.api.set_device_setting(setting).await;
+                let _ = reply.send(result.map_err(|e| e.to_string()));
+            }
+            SpeakerCommand::GetStatus(_)
+            | SpeakerCommand::Browse { .. }
+            | SpeakerCommand::GetFavourites(_)
+            | SpeakerCommand::GetDeviceInfo(_)
+            | SpeakerCommand::GetDeviceSettings(_)
+            | SpeakerCommand::PollUpdate(_) => unreachable!("queries aren't queued"),
+        }
+    }
+
+    // Keep track of what a status read found
+    fn observe(&mut self, status: &SpeakerStatus) {
+        self.source = status.source;
+        // Only a speaker that answers can say its update is done
+        self.updating = matches!(status.update, Some(FirmwareUpdate::Running { .. }));
+        self.remember_volume(status.source, status.volume);
+    }
+
+    fn sleep_deadline(&self) -> Option<Instant> {
+        self.sleep_timer.as_ref().map(|timer| timer.deadline)
+    }
+
+    // Fade from the current volume to `target` in the background, replacing any running fade
+    fn start_fade(&mut self, target: i32, duration: Duration, curve: FadeCurve) {
+        self.cancel_fade();
+        let target = self.limit_volume(target);
+        self.fade = Some(tokio::spawn(fade(
+            self.api.clone(),
+            target,
+            duration,
+            curve,
+        )));
+    }
+
+    fn fading(&self) -> bool {
+        self.fade.as_ref().is_some_and(|fade| !fade.is_finished())
+    }
+
+    // Note the volume in use on `source`. Volumes passing by during a fade are left out: they're
+    // on their way somewhere else, and the sleep timer would otherwise teach us to play at 0.
+    fn remember_volume(&mut self, source: Option<InputSource>, volume: Option<i32>) {
+        let (Some(source), Some(volume)) = (source, volume) else {
+            return;
+        };
+        if self.fading() || self.volumes.get(&source) == Some(&volume) {
+            return;
+        }
+        trace!("Remembering volume {} for {:?}", volume, source);
+        self.volumes.insert(source, volume);
+        if let Err(e) = store::save(VOLUMES, &self.volumes) {
+            warn!("Failed to save volumes: {}", e);
+        }
+    }
+
+    // Put back the volume last used on `input`, after switching to it. Only done on a switch,
+    // so a volume changed by hand afterwards stands until the next one.
+    async fn restore_volume(&mut self, input: InputSource) {
+        if !self.config.borrow().restore_volume || self.fading() {
+            return;
+        }
+        let Some(&volume) = self.volumes.get(&input) else {
+            return;
+        };
+        debug!("Restoring volume {} for {:?}", volume, input);
+        if let Err(e) = self.set_volume(volume).await {
+            error!("Failed to restore volume: {}", e);
+        }
+    }
+
+    fn cancel_fade(&mut self) {
+        if let Some(fade) = self.fade.take()
+            && !fade.is_finished()
+        {
+            debug!("Cancelling volume fade");
+            fade.abort();
+        }
+    }
+
+    // Returns the volume actually set, which the volume limit may have lowered
+    async fn set_volume(&self, volume: i32) -> Result<i32, Error> {
+        let volume = self.limit_volume(volume).clamp(0, 100);
+        self.api.set_volume(volume).await?;
+        Ok(volume)
+    }
+
+    // Power on and wait for the speaker to say it's on
+    async fn wake_speaker(&self) -> Result<(), SwitchError> {
+        self.api.power_on().await.map_err(SwitchError::PowerOn)?;
+        self.wait_until_ready().await
+    }
+
+    // A speaker coming out of standby accepts requests before it acts on them, and an input set
+    // too early is lost, so wait for speakerStatus to report it's on
+    async fn wait_until_ready(&self) -> Result<(), SwitchError> {
+        let timeout = self.config.borrow().power_on_timeout();
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.api.get_power().await {
+                Ok(power) if power == "powerOn" => return Ok(()),
+                Ok(power) => trace!("Speaker is {}, waiting for it to power on", power),
+                Err(e) => trace!("Speaker not answering while powering on: {}", e),
+            }
+            if Instant::now() >= deadline {
+                return Err(SwitchError::NotReady(timeout));
+            }
+            sleep(READY_POLL).await;
+        }
+    }
+
+    // Power on if needed, switch to `input` and put back the volume last used on it
+    async fn select_input(&mut self, input: InputSource) -> Result<(), SwitchError> {
+        let status = self.api.status().await.ok();
+        if let Some(status) = &status {
+            self.remember_volume(status.source, status.volume);
+        }
+        if let Some(status) = &status
+            && status.power == "standby"
+        {
+            debug!("Speaker is in standby, powering on first");
+            self.wake_speaker().await?;
+        }
+
+        let result = self.switch_input(input).await;
+        if result.is_ok() && status.and_then(|s| s.source) != Some(input) {
+            self.restore_volume(input).await;
+        }
+        // The new input may have a lower limit
+        self.enforce_volume_limit().await;
+        result
+    }
+
+    // Answers with the title of the favourite playing
+    async fn play_favourite(&mut self, id: &FavouriteId) -> Result<String, Error> {
+        let favourites = self.api.favourites().await?;
+        let favourite = find_favourite(&favourites, id)?.clone();
+        self.play(&favourite.roles).await?;
+        Ok(favourite.title)
+    }
+
+    // Play `roles` on the network player, which only plays on WiFi
+    async fn play(&mut self, roles: &serde_json::Value) -> Result<(), Error> {
+        self.select_input(InputSource::WiFi).await?;
+        self.api.play(roles).await
+    }
+
+    // Set the input and read it back, trying once more if it didn't take
+    async fn switch_input(&mut self, input: InputSource) -> Result<(), SwitchError> {
+        let mut source = None;
+        for attempt in 0..2 {
+            if attempt > 0 {
+                warn!(
+                    "Speaker is still on {:?}, setting {:?} again",
+                    source, input
+                );
+                sleep(READY_POLL).await;
+            }
+            self.api
+                .set_input(input)
+                .await
+                .map_err(SwitchError::SetInput)?;
+            source = self.api.get_source().await.map_err(SwitchError::Verify)?;
+            if source == Some(input) {
+                self.source = source;
+                return Ok(());
+            }
+        }
+        self.source = source;
+        Err(SwitchError::Unchanged(source))
+    }
+
+    // The volume limit in force right now, for the input we think is playing
+    fn volume_ceiling(&self) -> Option<i32> {
+        self.config
+            .borrow()
+            .volume_limit
+            .ceiling(self.source, Local::now().time())
+    }
+
+    fn limit_volume(&self, volume: i32) -> i32 {
+        match self.volume_ceiling() {
+            Some(ceiling) if volume > ceiling => {
+                info!("Volume {} is over the limit, using {}", volume, ceiling);
+                ceiling
+            }
+            _ => volume,
+        }
+    }
+
+    // Pull the volume back down if it was turned up past the limit from elsewhere (or the limit
+    // dropped, e.g. with the time of day), and keep the speaker's own maximum in step
+    async fn enforce_volume_limit(&mut self) {
+        let ceiling = self.volume_ceiling();
+        self.sync_max_volume(ceiling).await;
+        let Some(ceiling) = ceiling else {
+            return;
+        };
+
+        match self.api.get_volume().await {
+            Ok(volume) if volume > ceiling => {
+                info!(
+                    "Volume {} is over the limit of {}, turning it down",
+                    volume, ceiling
+                );
+                self.cancel_fade();
+                if let Err(e) = self.api.set_volume(ceiling).await {
+                    error!("Failed to set volume: {}", e);
                 }
-                SpeakerCommand::PowerOff => {
-                    info!("Powering off speakers");
-                    if let Err(e) = self.power_off().await {
-                        error!("Failed to power off: {}", e);
+            }
+            Ok(_) => {}
+            Err(e) => debug!("Failed to read volume for the limit check: {}", e),
+        }
+    }
+
+    async fn sync_max_volume(&mut self, ceiling: Option<i32>) {
+        if self.max_volume_unsupported {
+            return;
+        }
+        let sync = self.config.borrow().volume_limit.sync_speaker;
+        let max = match (sync, self.synced_max) {
+            (true, _) => ceiling.unwrap_or(100),
+            // Syncing was switched off, so hand the speaker its full range back
+            (false, Some(_)) => 100,
+            (false, None) => return,
+        };
+        if sync && self.synced_max == Some(max) {
+            return;
+        }
+
+        match self.api.set_max_volume(max).await {
+            Ok(()) => self.synced_max = sync.then_some(max),
+            Err(e) if rejected(&e) => {
+                warn!("Speaker has no maximum volume setting, not syncing: {}", e);
+                self.max_volume_unsupported = true;
+            }
+            // Tried again on the next limit check
+            Err(e) => debug!("Failed to set the speaker's maximum volume: {}", e),
+        }
+    }
+
+    async fn cancel_sleep_timer(&mut self) {
+        // Undo a fade that was under way
+        if let Some(SleepTimer {
+            fade_from: Some(volume),
+            ..
+        }) = self.sleep_timer.take()
+        {
+            self.cancel_fade();
+            if let Err(e) = self.set_volume(volume).await {
+                error!("Failed to restore volume: {}", e);
+            }
+        }
+    }
+
+    // Called when the sleep timer needs attention: to start fading out, or to power off
+    async fn sleep_timer_tick(&mut self) {
+        let Some(timer) = self.sleep_timer.as_mut() else {
+            return;
+        };
+
+        if timer.fade_start.take().is_some() {
+            let remaining = timer.deadline.saturating_duration_since(Instant::now());
+            match self.api.get_volume().await {
+                Ok(volume) => {
+                    debug!("Sleep timer fading out from volume {}", volume);
+                    if let Some(timer) = self.sleep_timer.as_mut() {
+                        timer.fade_from = Some(volume);
                     }
+                    self.start_fade(0, remaining, FadeCurve::EaseOut);
                 }
-                SpeakerCommand::PollUpdate(status) => {
-                    // This is handled by the UI, just log it
-                    trace!("Poll update received: {:?}", status);
+                Err(e) => warn!("Could not read the volume, not fading out: {}", e),
+            }
+            return;
+        }
+
+        let fade_from = timer.fade_from;
+        self.sleep_timer = None;
+        self.cancel_fade();
+        info!("Sleep timer expired, powering off");
+        if let Err(e) = self.api.power_off().await {
+            error!("Failed to power off: {}", e);
+        }
+        // Put the volume back, so the speaker isn't silent next time it's switched on
+        if let Some(volume) = fade_from
+            && let Err(e) = self.set_volume(volume).await
+        {
+            error!("Failed to restore volume: {}", e);
+        }
+    }
+}
+
+impl Api {
+    fn new(base_url: &str, config: &Config) -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout())
+            .timeout(config.request_timeout())
+            .build()
+            .unwrap_or_else(|e| {
+                warn!("Failed to create HTTP client with timeouts: {}", e);
+                reqwest::Client::new()
+            });
+        Self {
+            client,
+            base_url: base_url.to_string(),
+            retry: Retry {
+                retries: config.retries,
+                backoff: config.retry_backoff(),
+            },
+            health: Arc::default(),
+        }
+    }
+
+    // A single quick try, to see whether the speaker is there at all
+    fn probe(base_url: &str) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(PROBE_TIMEOUT)
+            .build()
+            .unwrap_or_else(|e| {
+                warn!("Failed to create HTTP client with timeouts: {}", e);
+                reqwest::Client::new()
+            });
+        Self {
+            client,
+            base_url: base_url.to_string(),
+            retry: Retry {
+                retries: 0,
+                backoff: Duration::ZERO,
+            },
+            health: Arc::default(),
+        }
+    }
+
+    fn connectivity(&self) -> Connectivity {
+        self.health
+            .lock()
+            .map(|health| health.clone())
+            .unwrap_or_default()
+    }
+
+    fn answered(&self, latency: Duration) {
+        if let Ok(mut health) = self.health.lock() {
+            if health.failures > 0 {
+                info!("Speaker is answering again");
+            }
+            *health = Connectivity {
+                last_contact: Some(std::time::Instant::now()),
+                failures: 0,
+                latency: Some(latency),
+            };
+        }
+    }
+
+    fn failed(&self) {
+        if let Ok(mut health) = self.health.lock() {
+            if health.failures == 0 {
+                warn!("Speaker is not answering");
+            }
+            health.failures += 1;
+        }
+    }
+
+    // Send a request to `endpoint` (getData, getRows or setData) and parse the JSON reply,
+    // retrying connection failures, timeouts and server errors with exponential backoff. Only for
+    // idempotent calls (reads, and setData writing a value), since a retry may repeat one the
+    // speaker already carried out; actions go through `request_once`.
+    async fn request(
+        &self,
+        endpoint: &str,
+        params: &[(&str, &str)],
+    ) -> Result<serde_json::Value, Error> {
+        self.send(endpoint, params, self.retry.retries).await
+    }
+
+    // Send a request without retrying it
+    async fn request_once(
+        &self,
+        endpoint: &str,
+        params: &[(&str, &str)],
+    ) -> Result<serde_json::Value, Error> {
+        self.send(endpoint, params, 0).await
+    }
+
+    async fn send(
+        &self,
+        endpoint: &str,
+        params: &[(&str, &str)],
+        retries: u32,
+    ) -> Result<serde_json::Value, Error> {
+        let url = format!("{}/api/{}", self.base_url, endpoint);
+        let mut backoff = self.retry.backoff.min(MAX_BACKOFF);
+        let mut attempt = 0;
+        loop {
+            let start = Instant::now();
+            let result = async {
+                self.client
+                    .get(&url)
+                    .query(params)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<serde_json::Value>()
+                    .await
+            }
+            .await;
+
+            match result {
+                Ok(json) => {
+                    self.answered(start.elapsed());
+                    return Ok(json);
+                }
+                Err(e) if attempt < retries && e.status().is_none_or(|s| s.is_server_error()) => {
+                    attempt += 1;
+                    debug!(
+                        "Request to {} failed, retry {} in {:?}: {}",
+                        url, attempt, backoff, e
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(e) => {
+                    // An error status is still an answer
+                    if e.status().is_some() {
+                        self.answered(start.elapsed());
+                    } else {
+                        self.failed();
+                    }
+                    return Err(e.into());
                 }
             }
         }
+    }
 
-        info!("Speaker controller shutting down");
+    /// Read the setting at `path`.
+    async fn get<T: Typed>(&self, path: &str) -> Result<T, Error> {
+        let json = self
+            .request("getData", &[("path", path), ("roles", "value")])
+            .await?;
+        debug!("{} is {}", path, json);
+        decode(path, json)
     }
 
-    async fn set_input(&self, input: InputSource) -> Result<(), Box<dyn std::error::Error>> {
-        let source = input.to_kef_source();
-        let value = json!({
-            "type": "kefPhysicalSource",
-            "kefPhysicalSource": source
-        });
-
-        let params = [
-            ("path", "settings:/kef/play/physicalSource"),
-            ("roles", "value"),
-            ("value", &value.to_string()),
-        ];
-
-        let response = self
-            .client
-            .get(format!("{}/api/setData", self.info.base_url))
-            .query(&params)
-            .send()
+    /// Write `value` to the setting at `path`.
+    // Answers with the speaker's reply, which is usually empty
+    async fn set<T: Typed>(&self, path: &str, value: T) -> Result<serde_json::Value, Error> {
+        let value = value.into_value().to_json().to_string();
+        let json = self
+            .request(
+                "setData",
+                &[("path", path), ("roles", "value"), ("value", &value)],
+            )
             .await?;
+        debug!("Set {} to {}: {}", path, value, json);
+        Ok(json)
+    }
 
-        let json: serde_json::Value = response.json().await?;
-        debug!(
-            "Set input response: {}",
-            serde_json::to_string_pretty(&json)?
-        );
+    // Every setting under `path`, depth first, going by the getRows API. Rows that are containers
+    // are walked into; the rest are settings.
+    async fn browse(&self, path: &str) -> Result<Vec<Setting>, Error> {
+        let mut settings = Vec::new();
+        let mut containers = vec![path.to_string()];
+
+        while let Some(container) = containers.pop() {
+            let mut from = 0;
+            let mut children = Vec::new();
+            loop {
+                let to = (from + BROWSE_PAGE).to_string();
+                let json = self
+                    .request(
+                        "getRows",
+                        &[
+                            ("path", &container),
+                            ("roles", "@all"),
+                            ("from", &from.to_string()),
+                            ("to", &to),
+                        ],
+                    )
+                    .await?;
+                trace!("Rows of {} from {}: {}", container, from, json);
+
+                let rows = json["rows"].as_array().cloned().unwrap_or_default();
+                let count = json["rowsCount"].as_u64().unwrap_or(0) as usize;
+                let empty = rows.is_empty();
+                from += rows.len();
+                for row in rows {
+                    let Some(path) = row["path"].as_str().map(str::to_string) else {
+                        continue;
+                    };
+                    let value = row.get("value").filter(|v| !v.is_null()).cloned();
+                    let kind = value
+                        .as_ref()
+                        .and_then(|v| v["type"].as_str())
+                        .or(row["type"].as_str())
+                        .unwrap_or("unknown")
+                        .to_string();
+                    if kind == "container" {
+                        children.push(path);
+                    } else {
+                        settings.push(Setting { path, kind, value });
+                    }
+                }
+                if empty || from >= count {
+                    break;
+                }
+            }
+
+            if settings.len() + containers.len() > BROWSE_LIMIT {
+                return Err(format!("more than {BROWSE_LIMIT} settings under {path}").into());
+            }
+            // Reversed so the first child is walked first
+            containers.extend(children.into_iter().rev());
+        }
+        Ok(settings)
+    }
+
+    // Read `path`, or write `value` to it, for SpeakerCommand::Raw
+    async fn raw(
+        &self,
+        path: &str,
+        value: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, String> {
+        let result = match value {
+            Some(value) => self.set(path, value).await,
+            None => self.get(path).await,
+        };
+        result.map_err(|e| e.to_string())
+    }
+
+    async fn set_input(&self, input: InputSource) -> Result<(), Error> {
+        self.set(PHYSICAL_SOURCE, input).await?;
         info!("Successfully set input to {:?}", input);
+        Ok(())
+    }
 
+    async fn power_on(&self) -> Result<(), Error> {
+        self.set(
+            PHYSICAL_SOURCE,
+            Value::PhysicalSource("powerOn".to_string()),
+        )
+        .await?;
+        info!("Successfully powered on speakers");
         Ok(())
     }
 
-    async fn power_on(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let value = json!({
-            "type": "kefPhysicalSource",
-            "kefPhysicalSource": "powerOn"
-        });
+    async fn power_off(&self) -> Result<(), Error> {
+        self.set(
+            PHYSICAL_SOURCE,
+            Value::PhysicalSource("standby".to_string()),
+        )
+        .await?;
+        info!("Successfully powered off speakers");
+        Ok(())
+    }
+
+    async fn set_max_volume(&self, volume: i32) -> Result<(), Error> {
+        let json = self.set(MAXIMUM_VOLUME, volume).await?;
+        if let Some(error) = json.get("error") {
+            return Err(format!("speaker refused it: {error}").into());
+        }
+        info!("Set the speaker's maximum volume to {}", volume);
+        Ok(())
+    }
 
-        let params = [
-            ("path", "settings:/kef/play/physicalSource"),
-            ("roles", "value"),
-            ("value", &value.to_string()),
-        ];
+    // Actions like starting an update are triggered rather than written. Doing one twice isn't
+    // the same as doing it once, so they're never retried.
+    async fn activate(&self, path: &str, value: serde_json::Value) -> Result<(), Error> {
+        let value = value.to_string();
+        let json = self
+            .request_once(
+                "setData",
+                &[("path", path), ("roles", "activate"), ("value", &value)],
+            )
+            .await?;
+        debug!("Activated {} with {}: {}", path, value, json);
+        Ok(())
+    }
 
-        let response = self
-            .client
-            .get(format!("{}/api/setData", self.info.base_url))
-            .query(&params)
-            .send()
+    async fn favourites(&self) -> Result<Vec<Favourite>, Error> {
+        // Far more than the speaker keeps
+        let to = BROWSE_PAGE.to_string();
+        let json = self
+            .request(
+                "getRows",
+                &[
+                    ("path", FAVOURITES),
+                    ("roles", "@all"),
+                    ("from", "0"),
+                    ("to", &to),
+                ],
+            )
             .await?;
+        let rows = json["rows"]
+            .as_array()
+            .ok_or_else(|| format!("unexpected reply for {FAVOURITES}: {json}"))?;
+        Ok(rows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, row)| {
+                Some(Favourite {
+                    index: i + 1,
+                    title: row["title"].as_str()?.to_string(),
+                    roles: row.clone(),
+                })
+            })
+            .collect())
+    }
 
-        let json: serde_json::Value = response.json().await?;
-        debug!(
-            "Power on response: {}",
-            serde_json::to_string_pretty(&json)?
-        );
-        info!("Successfully powered on speakers");
+    // Start playing what `roles` describes, like a favourite's row
+    async fn play(&self, roles: &serde_json::Value) -> Result<(), Error> {
+        self.activate(
+            PLAYER_CONTROL,
+            json!({"control": "play", "mediaRoles": roles}),
+        )
+        .await?;
+        info!("Started playing {}", roles["title"]);
+        Ok(())
+    }
+
+    async fn get_update(&self) -> Result<Option<FirmwareUpdate>, Error> {
+        let json: serde_json::Value = self.get(UPDATE_STATUS).await?;
+        Ok(firmware_update(&json))
+    }
 
+    async fn check_update(&self) -> Result<(), Error> {
+        self.activate(UPDATE_CHECK, json!({})).await?;
         Ok(())
     }
 
-    async fn power_off(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let value = json!({
-            "type": "kefPhysicalSource",
-            "kefPhysicalSource": "standby"
-        });
+    // What `check_update` found. The speaker asks KEF's servers, which takes a moment.
+    async fn checked_update(&self) -> Result<Option<FirmwareUpdate>, Error> {
+        let deadline = Instant::now() + UPDATE_CHECK_WAIT;
+        loop {
+            let json: serde_json::Value = self.get(UPDATE_STATUS).await?;
+            if json["state"] != "checking" || Instant::now() >= deadline {
+                return Ok(firmware_update(&json));
+            }
+            sleep(UPDATE_CHECK_POLL).await;
+        }
+    }
+
+    async fn start_update(&self) -> Result<(), Error> {
+        self.activate(UPDATE_START, json!({})).await?;
+        info!("Firmware update started");
+        Ok(())
+    }
 
-        let params = [
-            ("path", "settings:/kef/play/physicalSource"),
-            ("roles", "value"),
-            ("value", &value.to_string()),
-        ];
+    async fn get_device_info(&self, info: SpeakerInfo) -> Result<DeviceInfo, Error> {
+        // Make sure the speaker is there, so that anything missing below is missing from the
+        // firmware
+        self.get_power().await?;
+        let text = |v: &serde_json::Value| v.as_str().map(str::to_string);
 
-        let response = self
-            .client
-            .get(format!("{}/api/setData", self.info.base_url))
-            .query(&params)
-            .send()
-            .await?;
+        // Network details come back as a plain object rather than a typed value
+        let network: serde_json::Value = self.get(NETWORK_INFO).await.unwrap_or_default();
+        let ipv4 = &network["ipv4Config"];
+        Ok(DeviceInfo {
+            firmware: self.get(FIRMWARE).await.ok(),
+            serial: self.get(SERIAL_NUMBER).await.ok(),
+            mac_address: self.get(MAC_ADDRESS).await.ok(),
+            interface: text(&network["networkType"]),
+            signal: network["wireless"]["signalLevel"].as_i64(),
+            ip_address: text(&ipv4["address"]).unwrap_or(info.address),
+            netmask: text(&ipv4["subnetMask"]),
+            gateway: text(&ipv4["gateway"]),
+            dns: ["dnsServer1", "dnsServer2"]
+                .iter()
+                .filter_map(|key| text(&ipv4[key]))
+                .filter(|server| !server.is_empty())
+                .collect(),
+            dhcp: ipv4["assignment"].as_str().map(|a| a == "dhcp"),
+            name: info.name,
+            model: info.model,
+        })
+    }
 
-        let json: serde_json::Value = response.json().await?;
-        debug!(
-            "Power off response: {}",
-            serde_json::to_string_pretty(&json)?
-        );
-        info!("Successfully powered off speakers");
+    async fn get_device_settings(&self) -> Result<DeviceSettings, Error> {
+        // Settings this firmware doesn't have are left out, but a speaker that doesn't answer any
+        // of them is an error
+        let mut last_error = None;
+        let mut values = Vec::new();
+        for path in [
+            STANDBY_MODE,
+            STARTUP_TONE,
+            STANDBY_LED,
+            TOP_PANEL_LOCK,
+            AUTO_WAKE,
+        ] {
+            match self.get::<Value>(path).await {
+                Ok(value) => values.push(Some(value)),
+                Err(e) => {
+                    debug!("No device setting {}: {}", path, e);
+                    last_error = Some(e);
+                    values.push(None);
+                }
+            }
+        }
+        if let Some(e) = last_error
+            && values.iter().all(Option::is_none)
+        {
+            return Err(e);
+        }
 
+        let mut values = values.into_iter();
+        let mut next = || values.next().flatten();
+        Ok(DeviceSettings {
+            standby_mode: next().and_then(StandbyMode::from_value),
+            startup_tone: next().and_then(bool::from_value),
+            standby_led: next().and_then(bool::from_value).map(|disabled| !disabled),
+            panel_lock: next().and_then(bool::from_value),
+            auto_wake: next().and_then(bool::from_value),
+        })
+    }
+
+    async fn set_device_setting(&self, setting: DeviceSetting) -> Result<(), Error> {
+        match setting {
+            DeviceSetting::StandbyMode(mode) => self.set(STANDBY_MODE, mode).await,
+            DeviceSetting::StartupTone(on) => self.set(STARTUP_TONE, on).await,
+            DeviceSetting::StandbyLed(on) => self.set(STANDBY_LED, !on).await,
+            DeviceSetting::PanelLock(on) => self.set(TOP_PANEL_LOCK, on).await,
+            DeviceSetting::AutoWake(on) => self.set(AUTO_WAKE, on).await,
+        }?;
+        info!("Successfully changed device setting: {:?}", setting);
         Ok(())
     }
 
-    async fn get_speaker_status(&self) -> Result<SpeakerStatus, Box<dyn std::error::Error>> {
-        // Get power status
-        let params = [
-            ("path", "settings:/kef/host/speakerStatus"),
-            ("roles", "value"),
-        ];
+    async fn get_now_playing(&self) -> Result<Option<NowPlaying>, Error> {
+        // Player data comes back as a plain object rather than a typed value
+        let data: serde_json::Value = self.get("player:player/data").await?;
+        let Some(state) = data["state"].as_str() else {
+            return Ok(None);
+        };
+        let track = &data["trackRoles"];
+        let meta = &track["mediaData"]["metaData"];
+        let text = |v: &serde_json::Value| v.as_str().map(str::to_string);
+
+        Ok(Some(NowPlaying {
+            state: state.to_string(),
+            title: text(&track["title"]),
+            artist: text(&meta["artist"]),
+            album: text(&meta["album"]),
+        }))
+    }
 
-        let response = self
-            .client
-            .get(format!("{}/api/getData", self.info.base_url))
-            .query(&params)
-            .send()
-            .await?;
+    async fn get_power(&self) -> Result<String, Error> {
+        self.get(SPEAKER_STATUS).await
+    }
 
-        let power_json: serde_json::Value = response.json().await?;
-        debug!(
-            "Speaker power status response: {}",
-            serde_json::to_string_pretty(&power_json)?
-        );
+    // None in standby, or on a source qaf doesn't know
+    async fn get_source(&self) -> Result<Option<InputSource>, Error> {
+        let source: String = self.get(PHYSICAL_SOURCE).await?;
+        Ok(InputSource::from_kef_source(&source))
+    }
 
-        let power = power_json[0]["kefSpeakerStatus"]
-            .as_str()
-            .unwrap_or("unknown")
-            .to_string();
-
-        // Get current source if powered on
-        let source = if power == "powerOn" {
-            let params = [
-                ("path", "settings:/kef/play/physicalSource"),
-                ("roles", "value"),
-            ];
-
-            let response = self
-                .client
-                .get(format!("{}/api/getData", self.info.base_url))
-                .query(&params)
-                .send()
-                .await?;
-
-            let source_json: serde_json::Value = response.json().await?;
-            debug!(
-                "Speaker source response: {}",
-                serde_json::to_string_pretty(&source_json)?
-            );
+    async fn status(&self) -> Result<SpeakerStatus, Error> {
+        let power = self.get_power().await?;
+
+        // Get current source, volume and player state if powered on
+        let (source, volume, now_playing) = if power == "powerOn" {
+            (
+                self.get_source().await?,
+                Some(self.get_volume().await?),
+                self.get_now_playing().await?,
+            )
+        } else {
+            (None, None, None)
+        };
+
+        Ok(SpeakerStatus {
+            power,
+            source,
+            volume,
+            now_playing,
+            sleep_timer: None,
+            connectivity: Connectivity::default(),
+            update: None,
+        })
+    }
+
+    async fn set_volume(&self, volume: i32) -> Result<(), Error> {
+        let volume = volume.clamp(0, 100);
+        self.set(VOLUME, volume).await?;
+        info!("Successfully set volume to {}", volume);
+        Ok(())
+    }
+
+    async fn get_volume(&self) -> Result<i32, Error> {
+        self.get(VOLUME).await
+    }
+}
+
+/// A value as the KEF API types it on the wire, e.g. `{"type": "i32_", "i32_": 30}`.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    I32(i32),
+    I64(i64),
+    Double(f64),
+    Bool(bool),
+    String(String),
+    /// `kefPhysicalSource`: an input, or `powerOn`/`standby` when written.
+    PhysicalSource(String),
+    /// `kefSpeakerStatus`: `powerOn` or `standby`.
+    SpeakerStatus(String),
+    /// `kefStandbyMode`: like `standby_20mins`.
+    StandbyMode(String),
+    /// `kefEqProfileV2`, kept as the profile object.
+    EqProfile(serde_json::Value),
+    /// Anything untyped or of a type not listed here, kept as it came.
+    Other(serde_json::Value),
+}
+
+impl Value {
+    fn from_json(json: serde_json::Value) -> Self {
+        let Some(kind) = json["type"].as_str() else {
+            return Value::Other(json);
+        };
+        let inner = &json[kind];
+        let text = |v: &serde_json::Value| v.as_str().map(str::to_string);
+        let value = match kind {
+            "i32_" => inner
+                .as_i64()
+                .and_then(|v| i32::try_from(v).ok())
+                .map(Value::I32),
+            "i64_" => inner.as_i64().map(Value::I64),
+            "double_" => inner.as_f64().map(Value::Double),
+            "bool_" => inner.as_bool().map(Value::Bool),
+            "string_" => text(inner).map(Value::String),
+            "kefPhysicalSource" => text(inner).map(Value::PhysicalSource),
+            "kefSpeakerStatus" => text(inner).map(Value::SpeakerStatus),
+            "kefStandbyMode" => text(inner).map(Value::StandbyMode),
+            "kefEqProfileV2" => inner.is_object().then(|| Value::EqProfile(inner.clone())),
+            _ => None,
+        };
+        value.unwrap_or(Value::Other(json))
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let (kind, inner) = match self {
+            Value::I32(v) => ("i32_", json!(v)),
+            Value::I64(v) => ("i64_", json!(v)),
+            Value::Double(v) => ("double_", json!(v)),
+            Value::Bool(v) => ("bool_", json!(v)),
+            Value::String(v) => ("string_", json!(v)),
+            Value::PhysicalSource(v) => ("kefPhysicalSource", json!(v)),
+            Value::SpeakerStatus(v) => ("kefSpeakerStatus", json!(v)),
+            Value::StandbyMode(v) => ("kefStandbyMode", json!(v)),
+            Value::EqProfile(v) => ("kefEqProfileV2", v.clone()),
+            Value::Other(v) => return v.clone(),
+        };
+        let mut object = serde_json::Map::new();
+        object.insert("type".to_string(), json!(kind));
+        object.insert(kind.to_string(), inner);
+        serde_json::Value::Object(object)
+    }
+}
+
+/// Rust types settings can be read as and written from.
+trait Typed: Sized {
+    fn from_value(value: Value) -> Option<Self>;
+    fn into_value(self) -> Value;
+}
+
+impl Typed for Value {
+    fn from_value(value: Value) -> Option<Self> {
+        Some(value)
+    }
+
+    fn into_value(self) -> Value {
+        self
+    }
+}
 
-            let kef_source = source_json[0]["kefPhysicalSource"].as_str().unwrap_or("");
+impl Typed for i32 {
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::I32(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::I32(self)
+    }
+}
+
+impl Typed for bool {
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+// Reads any of the string types, writes string_
+impl Typed for String {
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::String(v)
+            | Value::PhysicalSource(v)
+            | Value::SpeakerStatus(v)
+            | Value::StandbyMode(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+
+impl Typed for InputSource {
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::PhysicalSource(v) => InputSource::from_kef_source(&v),
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::PhysicalSource(self.to_kef_source().to_string())
+    }
+}
+
+impl Typed for StandbyMode {
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::StandbyMode(v) => StandbyMode::from_kef_mode(&v),
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::StandbyMode(self.to_kef_mode().to_string())
+    }
+}
+
+// The raw JSON, for untyped values like the player data
+impl Typed for serde_json::Value {
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Other(json) => Some(json),
+            value => Some(value.to_json()),
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::from_json(self)
+    }
+}
+
+// getData answers with a list holding the one value asked for
+fn decode<T: Typed>(path: &str, json: serde_json::Value) -> Result<T, Error> {
+    let serde_json::Value::Array(mut values) = json else {
+        return Err(format!("unexpected reply for {path}: {json}").into());
+    };
+    if values.is_empty() {
+        return Err(format!("no value for {path}").into());
+    }
+    let value = Value::from_json(values.swap_remove(0));
+    T::from_value(value.clone())
+        .ok_or_else(|| format!("unexpected value for {path}: {}", value.to_json()).into())
+}
+
+// Whether the speaker answered and turned a request down, rather than not answering at all
+fn rejected(error: &Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .is_none_or(|e| e.status().is_some())
+}
+
+// The update state the speaker reports, e.g. `{"state": "downloading", "progress": 40}`
+fn firmware_update(json: &serde_json::Value) -> Option<FirmwareUpdate> {
+    match json["state"].as_str()? {
+        "updateAvailable" => Some(FirmwareUpdate::Available {
+            version: json["version"].as_str().map(str::to_string),
+        }),
+        "downloading" | "installing" => Some(FirmwareUpdate::Running {
+            progress: json["progress"].as_u64().unwrap_or(0).min(100) as u8,
+        }),
+        _ => None,
+    }
+}
+
+// What the speaker needs to play the stream at `url`, shown as `title`
+fn stream_roles(url: &str, title: &str) -> serde_json::Value {
+    json!({
+        "type": "audio",
+        "title": title,
+        "mediaData": {
+            "metaData": {"serviceID": "UrlPlayer", "live": true},
+            "resources": [{"uri": url}],
+        },
+    })
+}
+
+// The favourite at `id`'s position, or with its title. A name matches a title exactly, ignoring
+// case, or failing that, as part of one title.
+fn find_favourite<'a>(
+    favourites: &'a [Favourite],
+    id: &FavouriteId,
+) -> Result<&'a Favourite, Error> {
+    let name = match id {
+        FavouriteId::Index(index) => {
+            return favourites
+                .iter()
+                .find(|f| f.index == *index)
+                .ok_or_else(|| format!("there's no favourite {index}").into());
+        }
+        FavouriteId::Name(name) => name.to_lowercase(),
+    };
+    if let Some(favourite) = favourites.iter().find(|f| f.title.to_lowercase() == name) {
+        return Ok(favourite);
+    }
+    let mut matches = favourites
+        .iter()
+        .filter(|f| f.title.to_lowercase().contains(&name));
+    match (matches.next(), matches.next()) {
+        (Some(favourite), None) => Ok(favourite),
+        (Some(_), Some(_)) => Err(format!("more than one favourite matches {id}").into()),
+        (None, _) => Err(format!("no favourite matches {id}").into()),
+    }
+}
+
+// Turn down a write, answering anyone waiting for it
+fn refuse(command: SpeakerCommand, reason: &str) {
+    warn!("Refusing {:?}: {}", command, reason);
+    let reason = reason.to_string();
+    match command {
+        SpeakerCommand::Raw { reply, .. } => {
+            let _ = reply.send(Err(reason));
+        }
+        SpeakerCommand::PlayFavourite { reply, .. } => {
+            let _ = reply.send(Err(reason));
+        }
+        SpeakerCommand::CheckFirmwareUpdate(reply) => {
+            let _ = reply.send(Err(reason));
+        }
+        SpeakerCommand::SetDeviceName { reply, .. }
+        | SpeakerCommand::SetDeviceSetting { reply, .. }
+        | SpeakerCommand::PlayUrl { reply, .. }
+        | SpeakerCommand::StartFirmwareUpdate(reply) => {
+            let _ = reply.send(Err(reason));
+        }
+        _ => {}
+    }
+}
+
+// The speaker's info once it has announced its new `name`. If it doesn't in time, only the name
+// is changed.
+async fn renamed(info: SpeakerInfo, name: String) -> SpeakerInfo {
+    let wanted = name.clone();
+    let found = tokio::task::spawn_blocking(move || {
+        SpeakerController::discover_speaker(Some(&wanted), Some(RENAME_WAIT))
+    })
+    .await;
+    match found {
+        Ok(Some(found)) if found.address == info.address => {
+            info!("{} is now announced as {}", info.name, found.name);
+            found
+        }
+        _ => {
+            warn!("{} hasn't announced its new name {:?} yet", info.name, name);
+            SpeakerInfo { name, ..info }
+        }
+    }
+}
+
+// Background half of FadeVolume: step the volume from wherever it is to `target`. It stops early
+// if anyone else changes the volume, so a fade never fights someone with the remote.
+async fn fade(api: Api, target: i32, duration: Duration, curve: FadeCurve) {
+    let from = match api.get_volume().await {
+        Ok(volume) => volume,
+        Err(e) => {
+            error!("Failed to read volume: {}", e);
+            return;
+        }
+    };
+    let target = target.clamp(0, 100);
+    let start = Instant::now();
+    // Last volume we set; anything else on the speaker means it was changed by hand
+    let mut last_set = from;
 
-            InputSource::from_kef_source(kef_source)
+    loop {
+        if !duration.is_zero() {
+            sleep(FADE_STEP.min(duration)).await;
+        }
+        let progress = if duration.is_zero() {
+            1.0
         } else {
-            None
+            (start.elapsed().as_secs_f64() / duration.as_secs_f64()).min(1.0)
+        };
+        let volume = from + ((target - from) as f64 * curve.apply(progress)).round() as i32;
+
+        if volume != last_set {
+            match api.get_volume().await {
+                Ok(current) if current != last_set => {
+                    info!("Volume changed to {} by hand, stopping fade", current);
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to read volume: {}", e),
+            }
+            if let Err(e) = api.set_volume(volume).await {
+                error!("Failed to set volume: {}", e);
+            } else {
+                last_set = volume;
+            }
+        }
+
+        if progress >= 1.0 {
+            debug!("Volume fade done at {}", volume);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // getData replies recorded from an LSX II
+    const VOLUME_REPLY: &str = r#"[{"type":"i32_","i32_":35}]"#;
+    const SOURCE_REPLY: &str = r#"[{"type":"kefPhysicalSource","kefPhysicalSource":"wifi"}]"#;
+    const STANDBY_SOURCE_REPLY: &str =
+        r#"[{"type":"kefPhysicalSource","kefPhysicalSource":"standby"}]"#;
+    const STATUS_REPLY: &str = r#"[{"type":"kefSpeakerStatus","kefSpeakerStatus":"powerOn"}]"#;
+    const NAME_REPLY: &str = r#"[{"type":"string_","string_":"Office"}]"#;
+    const STARTUP_TONE_REPLY: &str = r#"[{"type":"bool_","bool_":false}]"#;
+    const STANDBY_MODE_REPLY: &str =
+        r#"[{"type":"kefStandbyMode","kefStandbyMode":"standby_20mins"}]"#;
+    const EQ_PROFILE_REPLY: &str = r#"[{"type":"kefEqProfileV2","kefEqProfileV2":{"bassExtension":"standard","deskMode":false,"deskModeSetting":-6.0,"isExpertMode":false,"phaseCorrection":true,"profileId":"f3a4b8b0","profileName":"Profile 1","subwooferCount":0,"trebleAmount":0.0,"wallMode":false,"wallModeSetting":-6.0}}]"#;
+    const PLAYER_DATA_REPLY: &str = r#"[{"state":"playing","status":{"duration":215000,"playSpeed":1},"trackRoles":{"title":"Teardrop","icon":"http://example/art.jpg","mediaData":{"metaData":{"artist":"Massive Attack","album":"Mezzanine","serviceID":"airplay"}}}}]"#;
+
+    fn reply(text: &str) -> serde_json::Value {
+        serde_json::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn decodes_typed_replies() {
+        assert_eq!(decode::<i32>(VOLUME, reply(VOLUME_REPLY)).unwrap(), 35);
+        assert_eq!(
+            decode::<InputSource>(PHYSICAL_SOURCE, reply(SOURCE_REPLY)).unwrap(),
+            InputSource::WiFi
+        );
+        assert_eq!(
+            decode::<String>(SPEAKER_STATUS, reply(STATUS_REPLY)).unwrap(),
+            "powerOn"
+        );
+        assert_eq!(
+            decode::<String>("settings:/deviceName", reply(NAME_REPLY)).unwrap(),
+            "Office"
+        );
+        assert!(!decode::<bool>(STARTUP_TONE, reply(STARTUP_TONE_REPLY)).unwrap());
+        assert_eq!(
+            decode::<StandbyMode>(STANDBY_MODE, reply(STANDBY_MODE_REPLY)).unwrap(),
+            StandbyMode::Minutes20
+        );
+    }
+
+    #[test]
+    fn standby_is_not_an_input() {
+        let source: String = decode(PHYSICAL_SOURCE, reply(STANDBY_SOURCE_REPLY)).unwrap();
+        assert_eq!(InputSource::from_kef_source(&source), None);
+        assert!(decode::<InputSource>(PHYSICAL_SOURCE, reply(STANDBY_SOURCE_REPLY)).is_err());
+    }
+
+    #[test]
+    fn eq_profile_round_trips() {
+        let original = reply(EQ_PROFILE_REPLY)[0].clone();
+        let value =
+            decode::<Value>("settings:/kef/dsp/v2/profile", reply(EQ_PROFILE_REPLY)).unwrap();
+        let Value::EqProfile(profile) = &value else {
+            panic!("not an EQ profile: {value:?}");
         };
+        assert_eq!(profile["profileName"], "Profile 1");
+        assert_eq!(value.to_json(), original);
+    }
+
+    #[test]
+    fn untyped_values_come_back_as_they_are() {
+        let data: serde_json::Value =
+            decode("player:player/data", reply(PLAYER_DATA_REPLY)).unwrap();
+        assert_eq!(data["state"], "playing");
+        assert_eq!(
+            data["trackRoles"]["mediaData"]["metaData"]["artist"],
+            "Massive Attack"
+        );
+        assert_eq!(
+            Value::from_json(data.clone()),
+            Value::Other(data.clone()),
+            "an object without a type stays untyped"
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_values() {
+        assert!(decode::<bool>(VOLUME, reply(VOLUME_REPLY)).is_err());
+        assert!(decode::<i32>(VOLUME, reply("[]")).is_err());
+        assert!(decode::<i32>(VOLUME, reply(r#"{"error":"not found"}"#)).is_err());
+        // An i32_ that doesn't fit isn't one
+        assert_eq!(
+            Value::from_json(json!({"type": "i32_", "i32_": 1_i64 << 40})),
+            Value::Other(json!({"type": "i32_", "i32_": 1_i64 << 40}))
+        );
+    }
+
+    #[test]
+    fn finds_favourites_by_index_or_name() {
+        let favourites: Vec<Favourite> = ["Office Radio", "Radio Paradise", "BBC Radio 6 Music"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, title)| Favourite {
+                index: i + 1,
+                title: title.to_string(),
+                roles: json!({"title": title}),
+            })
+            .collect();
+        let find = |id| find_favourite(&favourites, &id).map(|f| f.title.as_str());
+
+        assert_eq!(find(FavouriteId::Index(2)).unwrap(), "Radio Paradise");
+        assert!(find(FavouriteId::Index(4)).is_err());
+        assert_eq!(
+            find(FavouriteId::Name("office radio".to_string())).unwrap(),
+            "Office Radio"
+        );
+        assert_eq!(
+            find(FavouriteId::Name("6 music".to_string())).unwrap(),
+            "BBC Radio 6 Music"
+        );
+        assert!(
+            find(FavouriteId::Name("radio".to_string())).is_err(),
+            "matches all three"
+        );
+        assert!(find(FavouriteId::Name("jazz".to_string())).is_err());
+    }
+
+    #[test]
+    fn reads_firmware_update_state() {
+        assert_eq!(
+            firmware_update(&json!({"state": "updateAvailable", "version": "4.1"})),
+            Some(FirmwareUpdate::Available {
+                version: Some("4.1".to_string())
+            })
+        );
+        assert_eq!(
+            firmware_update(&json!({"state": "installing", "progress": 40})),
+            Some(FirmwareUpdate::Running { progress: 40 })
+        );
+        assert_eq!(firmware_update(&json!({"state": "noUpdate"})), None);
+        assert_eq!(firmware_update(&json!({})), None);
+    }
 
-        Ok(SpeakerStatus { power, source })
+    #[test]
+    fn encodes_values_for_set_data() {
+        assert_eq!(
+            30.into_value().to_json(),
+            json!({"type": "i32_", "i32_": 30})
+        );
+        assert_eq!(
+            InputSource::Optical.into_value().to_json(),
+            json!({"type": "kefPhysicalSource", "kefPhysicalSource": "optical"})
+        );
+        assert_eq!(
+            Value::PhysicalSource("powerOn".to_string()).to_json(),
+            json!({"type": "kefPhysicalSource", "kefPhysicalSource": "powerOn"})
+        );
+        assert_eq!(
+            true.into_value().to_json(),
+            json!({"type": "bool_", "bool_": true})
+        );
+        assert_eq!(
+            "Kitchen".to_string().into_value().to_json(),
+            json!({"type": "string_", "string_": "Kitchen"})
+        );
+        assert_eq!(
+            StandbyMode::Never.into_value().to_json(),
+            json!({"type": "kefStandbyMode", "kefStandbyMode": "standby_none"})
+        );
     }
 }
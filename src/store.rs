@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+
+use serde::{Serialize, de::DeserializeOwned};
+use tracing::{debug, warn};
+
+/// Where qaf keeps what it remembers between runs (one JSON file per kind of state) and its logs.
+pub fn path(name: &str) -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("qaf").join(name))
+}
+
+/// Read back a value saved with [`save`]. Missing or unreadable files give `None`.
+pub fn load<T: DeserializeOwned>(name: &str) -> Option<T> {
+    let path = path(name)?;
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to read {}: {}", path.display(), e);
+            }
+            return None;
+        }
+    };
+    match serde_json::from_str(&text) {
+        Ok(value) => {
+            debug!("Loaded {}", path.display());
+            Some(value)
+        }
+        Err(e) => {
+            warn!("Ignoring unreadable {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Save `value` under `name`, replacing what was there.
+pub fn save<T: Serialize>(name: &str, value: &T) -> Result<(), Box<dyn std::error::Error>> {
+    let path = path(name).ok_or("could not determine the data directory")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Write a temporary file and rename it, so a crash never leaves a truncated file behind
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_string_pretty(value)?)?;
+    std::fs::rename(&tmp, &path)?;
+    debug!("Saved {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,353 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    FirmwareUpdate, InputSource, SpeakerCommand, SpeakerInfo, SpeakerStatus, config::Config, store,
+};
+
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Gauge, List, ListItem, Paragraph},
+};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tracing::{debug, info};
+
+// Where the TUI's logs go while it has the terminal
+const LOG_FILE: &str = "tui.log";
+
+// How long to wait for a key press before checking for status updates
+const TICK: Duration = Duration::from_millis(250);
+// Volume change for a single press of +/-
+const VOLUME_STEP: i32 = 2;
+// Sleep timer settings to cycle through, in minutes
+const SLEEP_TIMER_STEPS: [u64; 4] = [15, 30, 60, 90];
+
+struct App {
+    info: watch::Receiver<Option<SpeakerInfo>>,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+    poll_rx: broadcast::Receiver<SpeakerStatus>,
+    config: watch::Receiver<Config>,
+    // Reply to the last status request we sent, if it hasn't arrived yet
+    pending: Option<oneshot::Receiver<SpeakerStatus>>,
+    status: Option<SpeakerStatus>,
+    // When `status` arrived, to count down the sleep timer between updates
+    status_at: Instant,
+    quit: bool,
+}
+
+impl App {
+    fn powered(&self) -> bool {
+        self.status.as_ref().is_some_and(|s| s.power == "powerOn")
+    }
+
+    // Ask the controller for a fresh status, e.g. after sending it a command
+    fn refresh(&mut self) {
+        let (status_tx, status_rx) = oneshot::channel();
+        let _ = self.speaker_tx.send(SpeakerCommand::GetStatus(status_tx));
+        self.pending = Some(status_rx);
+    }
+
+    fn process_updates(&mut self) {
+        while let Ok(status) = self.poll_rx.try_recv() {
+            debug!("Processing poll update: {:?}", status);
+            self.status = Some(status);
+            self.status_at = Instant::now();
+        }
+        if let Some(rx) = self.pending.as_mut() {
+            match rx.try_recv() {
+                Ok(status) => {
+                    self.status = Some(status);
+                    self.status_at = Instant::now();
+                    self.pending = None;
+                }
+                Err(oneshot::error::TryRecvError::Empty) => {}
+                Err(oneshot::error::TryRecvError::Closed) => self.pending = None,
+            }
+        }
+    }
+
+    fn handle_key(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('p') => self.toggle_power(),
+            KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Up => self.step_volume(VOLUME_STEP),
+            KeyCode::Char('-') | KeyCode::Down => self.step_volume(-VOLUME_STEP),
+            KeyCode::Char('s') => self.cycle_sleep_timer(),
+            KeyCode::Char('r') => self.refresh(),
+            KeyCode::Char(c) => {
+                let input = c.to_digit(10).and_then(|n| {
+                    let config = self.config.borrow();
+                    config
+                        .enabled_inputs
+                        .get((n as usize).checked_sub(1)?)
+                        .copied()
+                });
+                if let Some(input) = input {
+                    self.select_input(input);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn select_input(&mut self, input: InputSource) {
+        debug!("Input selected: {:?}", input);
+        let _ = self.speaker_tx.send(SpeakerCommand::SetInput(input));
+        // The controller powers the speaker on before switching, so reflect both right away
+        if let Some(status) = self.status.as_mut() {
+            status.power = "powerOn".to_string();
+            status.source = Some(input);
+        }
+        self.refresh();
+    }
+
+    fn toggle_power(&mut self) {
+        let is_powered = self.powered();
+        info!(
+            "Power toggled - current state: {}",
+            if is_powered { "on" } else { "off" }
+        );
+        if is_powered {
+            let _ = self.speaker_tx.send(SpeakerCommand::PowerOff);
+        } else {
+            let _ = self.speaker_tx.send(SpeakerCommand::PowerOn);
+        }
+        if let Some(status) = self.status.as_mut() {
+            if is_powered {
+                status.power = "standby".to_string();
+                status.source = None;
+            } else {
+                status.power = "powerOn".to_string();
+            }
+        }
+        self.refresh();
+    }
+
+    fn step_volume(&mut self, step: i32) {
+        let Some(volume) = self.status.as_mut().and_then(|s| s.volume.as_mut()) else {
+            return;
+        };
+        *volume = (*volume + step).clamp(0, 100);
+        let _ = self.speaker_tx.send(SpeakerCommand::SetVolume(*volume));
+    }
+
+    fn sleep_timer(&self) -> Option<Duration> {
+        let remaining = self.status.as_ref()?.sleep_timer?;
+        Some(remaining.saturating_sub(self.status_at.elapsed()))
+    }
+
+    // Set the sleep timer to the next step up from what's left on it, or cancel it after the last
+    fn cycle_sleep_timer(&mut self) {
+        let remaining = self.sleep_timer().unwrap_or_default();
+        let next = SLEEP_TIMER_STEPS
+            .into_iter()
+            .map(|minutes| Duration::from_secs(minutes * 60))
+            .find(|&step| step > remaining);
+        match next {
+            Some(duration) => {
+                info!("Setting sleep timer to {:?}", duration);
+                let _ = self.speaker_tx.send(SpeakerCommand::SleepTimer(duration));
+            }
+            None => {
+                info!("Cancelling sleep timer");
+                let _ = self.speaker_tx.send(SpeakerCommand::CancelSleepTimer);
+            }
+        }
+        self.refresh();
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let config = self.config.borrow();
+        let [header, sources, volume, playing, help] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(config.enabled_inputs.len() as u16 + 2),
+            Constraint::Length(3),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let current = self.status.as_ref().and_then(|s| s.source);
+        let power = match self.status.as_ref() {
+            Some(SpeakerStatus {
+                update: Some(FirmwareUpdate::Running { progress }),
+                ..
+            }) => Span::styled(
+                format!("updating firmware ({progress}%)"),
+                Style::new().fg(Color::Yellow).bold(),
+            ),
+            Some(status) if !status.connectivity.is_online() => Span::styled(
+                match status.connectivity.last_contact {
+                    Some(at) => format!("offline (seen {}s ago)", at.elapsed().as_secs()),
+                    None => "offline".to_string(),
+                },
+                Style::new().fg(Color::Red).bold(),
+            ),
+            status => match status.map(|s| s.power.as_str()) {
+                Some("powerOn") => Span::styled("on", Style::new().fg(Color::Green).bold()),
+                Some("standby") => Span::styled("standby", Style::new().fg(Color::Yellow)),
+                Some(other) => Span::styled(other.to_string(), Style::new().fg(Color::Red)),
+                None => Span::raw("…"),
+            },
+        };
+        let latency = self
+            .status
+            .as_ref()
+            .and_then(|s| s.connectivity.latency)
+            .map_or_else(String::new, |latency| {
+                format!(" ({} ms)", latency.as_millis())
+            });
+        frame.render_widget(
+            Paragraph::new(Line::from(vec![
+                Span::raw("Power: "),
+                power,
+                Span::styled(latency, Style::new().dim()),
+                Span::raw("   Source: "),
+                // Named here too, since the current source may be one that's hidden from the list
+                Span::raw(current.map_or("-", |input| config.label(input))),
+                Span::raw("   Sleep: "),
+                Span::raw(self.sleep_timer().map_or_else(
+                    || "off".to_string(),
+                    |left| format!("{}:{:02}", left.as_secs() / 60, left.as_secs() % 60),
+                )),
+            ]))
+            .block(Block::bordered().title(match self.info.borrow().as_ref() {
+                Some(info) => format!(" {} ({}) ", info.name, info.model),
+                None => " Looking for the speaker… ".to_string(),
+            })),
+            header,
+        );
+
+        let items: Vec<ListItem> = config
+            .enabled_inputs
+            .iter()
+            .enumerate()
+            .map(|(i, &input)| {
+                let line = format!(" {}  {}", i + 1, config.label(input));
+                if current == Some(input) {
+                    ListItem::new(format!("{line}  ●")).style(
+                        Style::new()
+                            .fg(Color::Black)
+                            .bg(Color::Cyan)
+                            .add_modifier(Modifier::BOLD),
+                    )
+                } else {
+                    ListItem::new(line)
+                }
+            })
+            .collect();
+        frame.render_widget(
+            List::new(items).block(Block::bordered().title(" Source ")),
+            sources,
+        );
+
+        let level = self.status.as_ref().and_then(|s| s.volume);
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title(" Volume "))
+                .gauge_style(Style::new().fg(Color::Cyan))
+                .percent(level.unwrap_or(0).clamp(0, 100) as u16)
+                .label(level.map_or_else(|| "-".to_string(), |v| v.to_string())),
+            volume,
+        );
+
+        let now_playing = self.status.as_ref().and_then(|s| s.now_playing.as_ref());
+        let lines = match now_playing {
+            Some(np) => {
+                let field = |name: &'static str, value: &Option<String>| {
+                    Line::from(vec![
+                        Span::styled(format!("{name:<7}"), Style::new().fg(Color::DarkGray)),
+                        Span::raw(value.clone().unwrap_or_default()),
+                    ])
+                };
+                vec![
+                    field("State", &Some(np.state.clone())),
+                    field("Title", &np.title),
+                    field("Artist", &np.artist),
+                    field("Album", &np.album),
+                ]
+            }
+            None => vec![Line::from("Nothing playing").fg(Color::DarkGray)],
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Now playing ")),
+            playing,
+        );
+
+        frame.render_widget(
+            Paragraph::new(format!(
+                " 1-{} source · p power · +/- volume · s sleep timer · r refresh · q quit",
+                config.enabled_inputs.len()
+            ))
+            .fg(Color::DarkGray),
+            help,
+        );
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> std::io::Result<()> {
+        self.refresh();
+        while !self.quit {
+            // The controller only stops if no speaker was found
+            if self.speaker_tx.is_closed() {
+                return Err(std::io::Error::other("no speaker found"));
+            }
+            self.process_updates();
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(TICK)?
+                && let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                self.handle_key(key.code);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where to write logs while the TUI has the terminal: appended to a file in the data directory,
+/// or dropped if it can't be opened.
+pub fn log_file() -> Box<dyn std::io::Write + Send> {
+    let file = store::path(LOG_FILE).and_then(|path| {
+        std::fs::create_dir_all(path.parent()?).ok()?;
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .ok()
+    });
+    match file {
+        Some(file) => Box::new(file),
+        None => Box::new(std::io::sink()),
+    }
+}
+
+pub fn run(
+    info: watch::Receiver<Option<SpeakerInfo>>,
+    tx: mpsc::UnboundedSender<SpeakerCommand>,
+    poll_rx: broadcast::Receiver<SpeakerStatus>,
+    config: watch::Receiver<Config>,
+) {
+    let app = App {
+        info,
+        speaker_tx: tx,
+        poll_rx,
+        config,
+        pending: None,
+        status: None,
+        status_at: Instant::now(),
+        quit: false,
+    };
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+
+    info!("TUI exiting");
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{SpeakerCommand, SpeakerStatus, config::Config};
+
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    time::Instant,
+};
+use tracing::{debug, info, warn};
+
+/// Put the speaker in standby once nothing has played on the current source for
+/// `idle_standby_mins`, going by the status updates from the poller. Inputs listed in
+/// `idle_standby_exempt` are left alone.
+pub async fn run(
+    mut status_rx: broadcast::Receiver<SpeakerStatus>,
+    speaker_tx: mpsc::UnboundedSender<SpeakerCommand>,
+    config: watch::Receiver<Config>,
+) {
+    // When the speaker went quiet, if it's quiet now
+    let mut idle_since: Option<Instant> = None;
+    let mut last_source = None;
+
+    loop {
+        let status = match status_rx.recv().await {
+            Ok(status) => status,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Idle watchdog missed {} status updates", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let (limit, exempt) = {
+            let config = config.borrow();
+            let exempt = status
+                .source
+                .is_some_and(|source| config.idle_standby_exempt.contains(&source));
+            (config.idle_standby(), exempt)
+        };
+        let playing = status
+            .now_playing
+            .as_ref()
+            .is_some_and(|np| np.state == "playing");
+        let switched = status.source != last_source;
+        last_source = status.source;
+
+        let Some(limit) = limit else {
+            idle_since = None;
+            continue;
+        };
+        // Switching source counts as activity: give the new one the full period
+        if status.power != "powerOn" || exempt || playing || switched {
+            idle_since = None;
+            continue;
+        }
+
+        let since = *idle_since.get_or_insert_with(Instant::now);
+        debug!("Speaker idle for {:?}", since.elapsed());
+        if since.elapsed() >= limit {
+            info!(
+                "Nothing played on {:?} for {:?}, going to standby",
+                status.source, limit
+            );
+            if speaker_tx.send(SpeakerCommand::PowerOff).is_err() {
+                break;
+            }
+            idle_since = None;
+        }
+    }
+
+    debug!("Idle watchdog stopping");
+}